@@ -0,0 +1,464 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::models::{
+    ClockSource, ControlMapping, Controller, FollowAction, FollowChoice, Instrument, ModDevice, ParameterValue,
+    Performance, PlaylistEnd, PlaylistEntry, Quantize, Route, Scene, Section, Sequence, SequenceStep, SysEx,
+    TempoRamp, Track, TrackFollow,
+};
+
+// Performance -------------------------------------------------------------------------------------
+
+/// Builds a performance in code. `build` runs the same validation as loading a performance file,
+/// so the result can be handed to the engine or written back to YAML.
+pub struct PerformanceBuilder {
+    perf: Performance,
+}
+
+impl PerformanceBuilder {
+    pub fn new() -> PerformanceBuilder {
+        PerformanceBuilder {
+            perf: Performance::new(),
+        }
+    }
+
+    pub fn controller(mut self, device: &str, channel: u8) -> PerformanceBuilder {
+        self.perf.controller = Controller {
+            device: device.to_string(),
+            channel,
+            ..self.perf.controller
+        };
+        self
+    }
+
+    pub fn reload(mut self, quantize: Quantize) -> PerformanceBuilder {
+        self.perf.controller.reload = Some(quantize);
+        self
+    }
+
+    /// Plays on the internal clock instead of MIDI clock from the controller, starting at `tempo`.
+    pub fn internal_clock(mut self, tempo: f64) -> PerformanceBuilder {
+        self.perf.controller.clock = Some(ClockSource::Internal);
+        self.perf.controller.tempo = Some(tempo);
+        self
+    }
+
+    /// Sends MIDI clock, start and stop to the device.
+    pub fn sync(mut self, device: &str) -> PerformanceBuilder {
+        self.perf.controller.sync.get_or_insert_with(Vec::new).push(device.to_string());
+        self
+    }
+
+    pub fn route(mut self, route: Route) -> PerformanceBuilder {
+        self.perf.routes.get_or_insert_with(Vec::new).push(route);
+        self
+    }
+
+    pub fn control(mut self, mapping: ControlMapping) -> PerformanceBuilder {
+        self.perf.control.get_or_insert_with(Vec::new).push(mapping);
+        self
+    }
+
+    /// Appends a scene to the playlist.
+    pub fn play(mut self, scene_name: &str) -> PerformanceBuilder {
+        self.perf.playlist.push(PlaylistEntry::scene(scene_name));
+        self
+    }
+
+    /// Appends an entry with repeats, a length or a section to the playlist.
+    pub fn play_entry(mut self, entry: PlaylistEntry) -> PerformanceBuilder {
+        self.perf.playlist.push(entry);
+        self
+    }
+
+    /// Adds a section that the playlist can play with `PlaylistEntry::section`.
+    pub fn section(mut self, name: &str, playlist: Vec<PlaylistEntry>) -> PerformanceBuilder {
+        self.perf.sections.get_or_insert_with(Vec::new).push(Section {
+            name: name.to_string(),
+            playlist,
+        });
+        self
+    }
+
+    pub fn end(mut self, end: PlaylistEnd) -> PerformanceBuilder {
+        self.perf.end = Some(end);
+        self
+    }
+
+    pub fn scene(mut self, scene: Scene) -> PerformanceBuilder {
+        self.perf.scenes.push(scene);
+        self
+    }
+
+    pub fn instrument(mut self, instrument: Instrument) -> PerformanceBuilder {
+        self.perf.instruments.push(instrument);
+        self
+    }
+
+    pub fn build(self) -> Result<Performance, String> {
+        self.perf.validate()?;
+        Ok(self.perf)
+    }
+}
+
+impl Default for PerformanceBuilder {
+    fn default() -> PerformanceBuilder {
+        PerformanceBuilder::new()
+    }
+}
+
+// Scene -------------------------------------------------------------------------------------------
+
+pub struct SceneBuilder {
+    scene: Scene,
+}
+
+impl SceneBuilder {
+    pub fn new(name: &str) -> SceneBuilder {
+        SceneBuilder {
+            scene: Scene::new(name),
+        }
+    }
+
+    /// Sets the number of bars instead of taking it from the first track.
+    pub fn length(mut self, bars: usize) -> SceneBuilder {
+        self.scene.length = Some(bars);
+        self
+    }
+
+    pub fn tempo(mut self, tempo: f64) -> SceneBuilder {
+        self.scene.tempo = Some(tempo);
+        self
+    }
+
+    /// Ramps the tempo to `to` over the given number of bars once the scene started.
+    pub fn ramp(mut self, to: f64, bars: usize) -> SceneBuilder {
+        self.scene.ramp = Some(TempoRamp { to, bars });
+        self
+    }
+
+    /// Adds a follow action for the end of the scene, picked at random by weight among all of them.
+    pub fn follow(mut self, action: FollowAction, weight: u32) -> SceneBuilder {
+        let choice = FollowChoice::Weighted { action, weight };
+        self.scene.follow.get_or_insert_with(Vec::new).push(choice);
+        self
+    }
+
+    /// Sends a SysEx message to its device when the scene starts.
+    pub fn sysex(mut self, sysex: SysEx) -> SceneBuilder {
+        self.scene.sysex.get_or_insert_with(Vec::new).push(sysex);
+        self
+    }
+
+    pub fn track(mut self, track: Track) -> SceneBuilder {
+        self.scene.tracks.push(track);
+        self
+    }
+
+    pub fn build(self) -> Result<Scene, String> {
+        self.scene.validate()?;
+        Ok(self.scene)
+    }
+}
+
+// Track -------------------------------------------------------------------------------------------
+
+pub struct TrackBuilder {
+    track: Track,
+}
+
+impl TrackBuilder {
+    pub fn new(instrument: &str) -> TrackBuilder {
+        TrackBuilder {
+            track: Track {
+                instrument: instrument.to_string(),
+                follow: None,
+                play: Vec::new(),
+                fill: None,
+            },
+        }
+    }
+
+    /// Advances the track whenever the given instrument plays a note, instead of on every bar.
+    pub fn follow(mut self, instrument: &str) -> TrackBuilder {
+        self.track.follow = Some(TrackFollow::Instrument(instrument.to_string()));
+        self
+    }
+
+    /// Follows an instrument with options, see `FollowOptions`.
+    pub fn follow_with(mut self, follow: TrackFollow) -> TrackBuilder {
+        self.track.follow = Some(follow);
+        self
+    }
+
+    /// Appends a sequence to play for one bar.
+    pub fn play(mut self, sequence_name: &str) -> TrackBuilder {
+        self.track.play.push(sequence_name.to_string());
+        self
+    }
+
+    /// Plays the sequence instead while a fill is active.
+    pub fn fill(mut self, sequence_name: &str) -> TrackBuilder {
+        self.track.fill = Some(sequence_name.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Track, String> {
+        self.track.validate()?;
+        Ok(self.track)
+    }
+}
+
+// Instrument --------------------------------------------------------------------------------------
+
+pub struct InstrumentBuilder {
+    instrument: Instrument,
+}
+
+impl InstrumentBuilder {
+    pub fn new(name: &str, device: &str, channel: u8) -> InstrumentBuilder {
+        let mut instrument = Instrument::new();
+        instrument.name = name.to_string();
+        instrument.device = device.to_string();
+        instrument.channel = channel;
+        InstrumentBuilder { instrument }
+    }
+
+    /// Adds a data lane, so the next value of each step's `data` is sent as this controller.
+    pub fn data(mut self, device: &str, channel: u8, control: u8) -> InstrumentBuilder {
+        self.instrument.data.get_or_insert_with(Vec::new).push(ModDevice {
+            device: device.to_string(),
+            channel,
+            control,
+        });
+        self
+    }
+
+    pub fn sequence(mut self, sequence: Sequence) -> InstrumentBuilder {
+        self.instrument.sequences.push(sequence);
+        self
+    }
+
+    pub fn build(self) -> Result<Instrument, String> {
+        self.instrument.validate()?;
+        Ok(self.instrument)
+    }
+}
+
+// Sequence ----------------------------------------------------------------------------------------
+
+pub struct SequenceBuilder {
+    sequence: Sequence,
+}
+
+impl SequenceBuilder {
+    pub fn new(name: &str) -> SequenceBuilder {
+        let mut sequence = Sequence::new();
+        sequence.name = name.to_string();
+        SequenceBuilder { sequence }
+    }
+
+    pub fn step(mut self, step: SequenceStep) -> SequenceBuilder {
+        self.sequence.steps.push(Some(step));
+        self
+    }
+
+    pub fn rest(mut self) -> SequenceBuilder {
+        self.sequence.steps.push(None);
+        self
+    }
+
+    pub fn build(self) -> Result<Sequence, String> {
+        self.sequence.validate()?;
+        Ok(self.sequence)
+    }
+}
+
+// SequenceStep ------------------------------------------------------------------------------------
+
+/// Builds a single step. Pitches are note names like `C5` or MIDI note numbers, as in YAML.
+pub struct StepBuilder {
+    step: SequenceStep,
+}
+
+impl StepBuilder {
+    pub fn new() -> StepBuilder {
+        StepBuilder {
+            step: SequenceStep::new(),
+        }
+    }
+
+    pub fn pitch(mut self, pitch: &str) -> StepBuilder {
+        self.step.pitch.get_or_insert_with(Vec::new).push(pitch.to_string());
+        self
+    }
+
+    pub fn velocity(mut self, velocity: u8) -> StepBuilder {
+        self.step.velocity = Some(velocity.to_string());
+        self
+    }
+
+    pub fn data(mut self, values: Vec<u8>) -> StepBuilder {
+        self.step.data = Some(values);
+        self
+    }
+
+    pub fn program(mut self, program: u8) -> StepBuilder {
+        self.step.program = Some(program);
+        self
+    }
+
+    /// Plays the step only during fills, or only outside of them.
+    pub fn fill(mut self, fill: bool) -> StepBuilder {
+        self.step.fill = Some(fill);
+        self
+    }
+
+    /// Bends the pitch from -8192 to 8191 before the notes.
+    pub fn bend(mut self, bend: i16) -> StepBuilder {
+        self.step.bend = Some(bend);
+        self
+    }
+
+    pub fn aftertouch(mut self, pressure: u8) -> StepBuilder {
+        self.step.aftertouch = Some(pressure);
+        self
+    }
+
+    pub fn poly_aftertouch(mut self, pressure: u8) -> StepBuilder {
+        self.step.poly_aftertouch = Some(pressure);
+        self
+    }
+
+    pub fn nrpn(mut self, number: u16, value: u16) -> StepBuilder {
+        self.step.nrpn.get_or_insert_with(Vec::new).push(ParameterValue { number, value });
+        self
+    }
+
+    pub fn rpn(mut self, number: u16, value: u16) -> StepBuilder {
+        self.step.rpn.get_or_insert_with(Vec::new).push(ParameterValue { number, value });
+        self
+    }
+
+    /// Sets a 14-bit controller 0 to 31 and its LSB controller.
+    pub fn cc14(mut self, control: u8, value: u16) -> StepBuilder {
+        let number = control as u16;
+        self.step.cc14.get_or_insert_with(Vec::new).push(ParameterValue { number, value });
+        self
+    }
+
+    /// Sends a SysEx message before the notes, to the instrument's device unless it names another.
+    pub fn sysex(mut self, sysex: SysEx) -> StepBuilder {
+        self.step.sysex.get_or_insert_with(Vec::new).push(sysex);
+        self
+    }
+
+    pub fn build(self) -> SequenceStep {
+        self.step
+    }
+}
+
+impl Default for StepBuilder {
+    fn default() -> StepBuilder {
+        StepBuilder::new()
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::{
+        InstrumentBuilder, PerformanceBuilder, SceneBuilder, SequenceBuilder, StepBuilder, TrackBuilder,
+    };
+    use crate::models::{FollowAction, Performance, PlaylistEntry, Quantize, SysEx, TrackFollow};
+
+    fn performance() -> Result<Performance, String> {
+        let kick = SequenceBuilder::new("A")
+            .step(StepBuilder::new().pitch("C3").velocity(110).data(vec![64]).build())
+            .rest()
+            .step(StepBuilder::new().pitch("36").build())
+            .rest()
+            .build()?;
+
+        PerformanceBuilder::new()
+            .controller("IAC Driver Bus 1", 7)
+            .reload(Quantize::Scene)
+            .play("intro")
+            .scene(
+                SceneBuilder::new("intro")
+                    .track(TrackBuilder::new("drum1").play("A").build()?)
+                    .track(TrackBuilder::new("bass").follow("drum1").play("A").play("B").build()?)
+                    .build()?,
+            )
+            .instrument(
+                InstrumentBuilder::new("drum1", "828x MIDI Port", 10)
+                    .data("IAC1", 3, 40)
+                    .sequence(kick)
+                    .build()?,
+            )
+            .build()
+    }
+
+    #[test]
+    fn test_build_performance() {
+        let perf = performance().unwrap();
+        assert_eq!(perf.controller.channel, 7);
+        assert_eq!(perf.controller.reload, Some(Quantize::Scene));
+        assert_eq!(perf.playlist, vec![PlaylistEntry::scene("intro")]);
+        assert_eq!(perf.scenes[0].tracks[1].follow, Some(TrackFollow::Instrument(String::from("drum1"))));
+
+        let steps = &perf.instruments[0].sequences[0].steps;
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[0].as_ref().unwrap().velocity, Some(String::from("110")));
+        assert!(steps[1].is_none());
+
+        let yaml_text = serde_yaml::to_string(&perf).unwrap();
+        assert_eq!(serde_yaml::from_str::<Performance>(&yaml_text).unwrap(), perf);
+    }
+
+    #[test]
+    fn test_build_validation() {
+        assert_eq!(
+            TrackBuilder::new("drum1").build().unwrap_err(),
+            "track \"drum1\" plays no sequences"
+        );
+        assert_eq!(
+            InstrumentBuilder::new("drum1", "synth", 0).build().unwrap_err(),
+            "instrument \"drum1\": channel 0 is not between 1 and 16"
+        );
+        assert!(SequenceBuilder::new("A").build().is_err());
+        assert_eq!(
+            SequenceBuilder::new("A").rest().step(StepBuilder::new().cc14(40, 1).build()).build().unwrap_err(),
+            "sequence \"A\" step 2: controller 40 has no LSB controller"
+        );
+        assert!(SceneBuilder::new("intro").build().is_err());
+        let track = TrackBuilder::new("drum1").play("A").build().unwrap();
+        assert_eq!(
+            SceneBuilder::new("intro").track(track).sysex(SysEx::new("F0 7E 7F 09 01 F7")).build().unwrap_err(),
+            "scene \"intro\" sends sysex without a device"
+        );
+        let track = TrackBuilder::new("drum1").play("A").build().unwrap();
+        let scene = SceneBuilder::new("intro").track(track).follow(FollowAction::Next, u32::MAX);
+        assert_eq!(
+            scene.follow(FollowAction::Loop, u32::MAX).build().unwrap_err(),
+            "scene \"intro\" has follow weights above 4294967295 in total"
+        );
+        assert_eq!(PerformanceBuilder::new().build().unwrap_err(), "playlist is empty");
+    }
+}
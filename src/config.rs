@@ -31,4 +31,43 @@ pub const DEFAULT_VELOCITY: u8 = 100;
 pub const CLOCK_MULTIPLIER: u64 = 84;
 pub const DEFAULT_PARTS_PER_QUARTER: u64 = 24;
 
-pub const TICKS_PER_MEASURE: u64 = CLOCK_MULTIPLIER * DEFAULT_PARTS_PER_QUARTER * 4;
+pub const TICKS_PER_BEAT: u64 = CLOCK_MULTIPLIER * DEFAULT_PARTS_PER_QUARTER;
+pub const TICKS_PER_MEASURE: u64 = TICKS_PER_BEAT * 4;
+
+/// The clock multiplier blocks until this long before each tick and spins for the rest.
+pub const CLOCK_SPIN_MARGIN_US: u64 = 100;
+
+/// The tempo assumed until the second clock arrives, and of the internal clock unless set.
+pub const DEFAULT_TEMPO_BPM: u64 = 120;
+pub const MIN_TEMPO_BPM: f64 = 20.0;
+pub const MAX_TEMPO_BPM: f64 = 300.0;
+/// The loop bandwidth of the tempo follower, per clock. Higher values follow tempo ramps more
+/// closely, lower values smooth out more jitter.
+pub const TEMPO_BANDWIDTH: f64 = 0.15;
+/// Clocks further than this fraction of a period from their predicted time are outliers.
+pub const TEMPO_OUTLIER_RATIO: f64 = 0.25;
+/// Consecutive outliers after which the tempo follower assumes a tempo jump and locks again.
+pub const TEMPO_RELOCK_OUTLIERS: usize = 3;
+
+/// Every step is split into a note-on and a note-off half, each at least one tick long.
+pub const MAX_SEQUENCE_STEPS: usize = TICKS_PER_MEASURE as usize / 2;
+
+// MIDI Devices ------------------------------------------------------------------------------------
+
+pub const DEFAULT_DEVICE_SCAN_INTERVAL_MS: u64 = 2_000;
+
+pub const MIDI_POLL_ACTIVE_US: u64 = 50;
+pub const MIDI_POLL_IDLE_MS: u64 = 1;
+pub const MIDI_ACTIVE_WINDOW_MS: u64 = 250;
+
+/// Outputs whose writes failed are reopened at most this often.
+pub const OUTPUT_RETRY_MS: u64 = 1_000;
+
+// Stats -------------------------------------------------------------------------------------------
+
+pub const STATS_SEND_INTERVAL_MS: u64 = 1_000;
+pub const STATS_REPORT_INTERVAL_MS: u64 = 10_000;
+
+// Performance File --------------------------------------------------------------------------------
+
+pub const FILE_WATCH_DEBOUNCE_MS: u64 = 100;
@@ -16,9 +16,14 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use crate::config::DEFAULT_DEVICE_SCAN_INTERVAL_MS;
+
 pub struct Context {
     pub performance: String,
     pub debug: bool,
+    pub strict: bool,
+    pub device_scan_interval: u64,
+    pub stats: bool,
 }
 
 impl Context {
@@ -26,6 +31,15 @@ impl Context {
         Context {
             performance: String::new(),
             debug: false,
+            strict: false,
+            device_scan_interval: DEFAULT_DEVICE_SCAN_INTERVAL_MS,
+            stats: false,
         }
     }
 }
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
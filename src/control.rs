@@ -0,0 +1,116 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+extern crate portmidi;
+
+use portmidi::MidiMessage;
+
+use crate::config::DEFAULT_MIDI_CHANNEL;
+use crate::midi::{control_change, is_channel_message, note_on, parse_channel, parse_midi_note, parse_status};
+use crate::models::{ControlMapping, Quantize};
+
+// Control Mapping ---------------------------------------------------------------------------------
+
+/// Returns true if `message` from `device_name` triggers the mapping. Notes trigger on note-on,
+/// controllers when their value is 64 or above (a button press) and programs on any change.
+pub fn mapping_matches(mapping: &ControlMapping, device_name: &String, message: MidiMessage) -> bool {
+    if &mapping.device != device_name || !is_channel_message(message.status) {
+        return false;
+    }
+    if mapping.channel.is_some_and(|c| c - 1 != parse_channel(message.status)) {
+        return false;
+    }
+
+    match parse_status(message.status) {
+        0x90 => {
+            message.data2 > 0
+                && mapping.note.as_ref().is_some_and(|n| parse_midi_note(n) == message.data1)
+        }
+        0xB0 => message.data2 >= 64 && mapping.control == Some(message.data1),
+        0xC0 => mapping.program == Some(message.data1),
+        _ => false,
+    }
+}
+
+pub fn mapping_quantize(mapping: &ControlMapping) -> Quantize {
+    mapping.quantize.unwrap_or(Quantize::Bar)
+}
+
+/// Builds the message that lights the mapping's button, using the `led: [off, on]` values as the
+/// note velocity or controller value.
+pub fn feedback_message(mapping: &ControlMapping, active: bool) -> Option<MidiMessage> {
+    let led = mapping.led.as_ref()?;
+    let value = *led.get(if active { 1 } else { 0 })?;
+    let channel = mapping.channel.unwrap_or(DEFAULT_MIDI_CHANNEL) - 1;
+
+    match (&mapping.note, mapping.control) {
+        (Some(note), _) => Some(note_on(channel, parse_midi_note(note), value)),
+        (None, Some(control)) => Some(control_change(channel, control, value)),
+        (None, None) => None,
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::control::{feedback_message, mapping_matches};
+    use crate::midi::{control_change, note_off, note_on, program_change};
+    use crate::models::{ControlAction, ControlMapping};
+
+    fn mapping() -> ControlMapping {
+        ControlMapping {
+            device: String::from("pads"),
+            channel: Some(10),
+            note: Some(String::from("C3")),
+            control: Some(20),
+            program: None,
+            action: ControlAction::Next,
+            quantize: None,
+            led: None,
+        }
+    }
+
+    #[test]
+    fn test_mapping_matches() {
+        let m = mapping();
+        let device = String::from("pads");
+        assert!(mapping_matches(&m, &device, note_on(9, 36, 100)));
+        assert!(!mapping_matches(&m, &device, note_on(9, 36, 0)));
+        assert!(!mapping_matches(&m, &device, note_off(9, 36, 0)));
+        assert!(!mapping_matches(&m, &device, note_on(0, 36, 100)));
+        assert!(!mapping_matches(&m, &String::from("keys"), note_on(9, 36, 100)));
+
+        assert!(mapping_matches(&m, &device, control_change(9, 20, 127)));
+        assert!(!mapping_matches(&m, &device, control_change(9, 20, 0)));
+        assert!(!mapping_matches(&m, &device, program_change(9, 20)));
+    }
+
+    #[test]
+    fn test_feedback_message() {
+        let mut m = mapping();
+        assert_eq!(feedback_message(&m, true), None);
+
+        m.led = Some(vec![0, 60]);
+        assert_eq!(feedback_message(&m, true), Some(note_on(9, 36, 60)));
+        assert_eq!(feedback_message(&m, false), Some(note_on(9, 36, 0)));
+
+        m.note = None;
+        assert_eq!(feedback_message(&m, true), Some(control_change(9, 20, 60)));
+    }
+}
@@ -18,75 +18,142 @@
  */
 extern crate portmidi;
 
-use std::sync::mpsc::{channel, Receiver, Sender};
 use std::{thread, time};
 
+use crossbeam_channel::{after, never, select, unbounded, Receiver, Sender};
 use spin_sleep;
 
-use crate::config::{CLOCK_MULTIPLIER, DEFAULT_PARTS_PER_QUARTER};
+use crate::config::{
+    CLOCK_MULTIPLIER, CLOCK_SPIN_MARGIN_US, DEFAULT_PARTS_PER_QUARTER, DEFAULT_TEMPO_BPM, TICKS_PER_BEAT,
+};
 use crate::context::Context;
+use crate::control::{mapping_matches, mapping_quantize};
 use crate::log;
-use crate::midi::start_midi_listener;
-use crate::models::Controller;
-use crate::performance::start_performance;
-
-// -------------------------------------------------------------------------------------------------
-
-pub fn average(values: &[f64]) -> f64 {
-    let mut avg = 0.0;
-    for i in 0..values.len() {
-        avg += values[i]
-    }
-    avg / values.len() as f64
-}
+use crate::midi::{is_channel_message, start_midi_listener};
+use crate::models::{
+    ClockSource, ControlAction, ControlMapping, Controller, MidiInput, Performance, Quantize, Route,
+};
+use crate::performance::{start_performance, PerformanceChannels};
+use crate::router::start_router;
+use crate::stats::{start_stats_reporter, StatsCollector, TimingStats};
+use crate::tempo::{TempoFollower, TickSchedule};
 
 // Clock Multiplier --------------------------------------------------------------------------------
 
+/// Sends `CLOCK_MULTIPLIER` ticks for every MIDI clock, spread until the arrival of the next clock
+/// predicted by the tempo follower. Ticks left over when a clock arrives early are spread over the
+/// following clock, see `TickSchedule`. The thread blocks until shortly before each tick and only
+/// spins for the last `CLOCK_SPIN_MARGIN_US`, and blocks entirely while no clock is running. Each
+/// tick is sent with the time it was due, and how late it was sent is recorded in the stats.
 pub fn start_clock_multiplier(
-    clock_recv: Receiver<u64>,
+    clock_recv: Receiver<(time::Instant, time::Instant)>,
     clock_active: Receiver<bool>,
-    clock_send: Sender<u64>,
+    clock_send: Sender<(u64, time::Instant)>,
+    stats_send: Option<Sender<TimingStats>>,
 ) {
+    let spin_margin = time::Duration::from_micros(CLOCK_SPIN_MARGIN_US);
     let mut clock_enabled = false;
-    let mut tick_duration: time::Duration = time::Duration::from_micros(0);
-    let mut tick_counter: u64 = 0;
+    let mut schedule = TickSchedule::new();
 
     thread::spawn(move || {
+        let mut stats = StatsCollector::new(stats_send);
+
         loop {
-            let clock_active_msg = clock_active.try_recv();
-            if clock_active_msg.is_ok() {
-                clock_enabled = clock_active_msg.unwrap();
+            stats.send_due();
+            let next_tick = if clock_enabled { schedule.next_tick() } else { None };
+            let tick_timer = match next_tick {
+                Some((_, due)) => {
+                    after(due.saturating_duration_since(time::Instant::now()).saturating_sub(spin_margin))
+                }
+                None => never(),
+            };
+
+            select! {
+                recv(clock_active) -> msg => {
+                    clock_enabled = msg.expect("clock_multiplier clock_active closed");
+                    if !clock_enabled {
+                        schedule.reset();
+                    }
+                }
+                recv(clock_recv) -> msg => {
+                    let (clock_time, next_clock) = msg.expect("clock_multiplier clock_recv closed");
+                    clock_enabled = true;
+                    let carried = schedule.clock(clock_time, next_clock);
+                    if carried > 0 {
+                        stats.stats.early_clocks += 1;
+                        stats.stats.carried_ticks += carried;
+                    }
+                }
+                recv(tick_timer) -> _ => {
+                    if let Some((tick, due)) = next_tick {
+                        spin_sleep::sleep(due.saturating_duration_since(time::Instant::now()));
+                        stats.stats.tick_latency.record(time::Instant::now().saturating_duration_since(due));
+                        clock_send
+                            .send((tick % CLOCK_MULTIPLIER, due))
+                            .expect("clock_multiplier send tick failed");
+                        schedule.advance();
+                    }
+                }
             }
+        }
+    });
+}
 
-            let clock_msg = clock_recv.try_recv();
-            if clock_msg.is_ok() {
-                let clock_duration = clock_msg.unwrap();
-                let micros = clock_duration / CLOCK_MULTIPLIER;
-                tick_duration = time::Duration::from_micros(micros);
+// Internal Clock ----------------------------------------------------------------------------------
 
-                // flush remaining ticks in case the clock arrived sooner than expected
-                while clock_enabled && tick_counter < CLOCK_MULTIPLIER {
-                    // println!("⚠️  FLUSH CLOCK {}/{} ticks micros={}", tick_counter, CLOCK_MULTIPLIER, micros);
-                    clock_send
-                        .send(tick_counter)
-                        .expect("clock_multiplier send tick failed");
-                    tick_counter += 1;
-                }
+/// Sends ticks at the tempo that arrives on `tempo_recv`, starting at `tempo`. Like the clock
+/// multiplier it blocks until shortly before each tick and spins for the rest. A stop pauses the
+/// clock and a start resumes it from the first tick. If the thread falls behind by more than a
+/// MIDI clock, it continues from the current time instead of catching up.
+pub fn start_internal_clock(
+    tempo: f64,
+    tempo_recv: Receiver<f64>,
+    clock_active: Receiver<bool>,
+    clock_send: Sender<(u64, time::Instant)>,
+    stats_send: Option<Sender<TimingStats>>,
+) {
+    let spin_margin = time::Duration::from_micros(CLOCK_SPIN_MARGIN_US);
+    let tick_duration = |tempo: f64| time::Duration::from_nanos((60e9 / (tempo * TICKS_PER_BEAT as f64)) as u64);
 
-                clock_enabled = true;
-                tick_counter = 0;
-            }
+    thread::spawn(move || {
+        let mut stats = StatsCollector::new(stats_send);
+        let mut running = true;
+        let mut tick_counter: u64 = 0;
+        let mut next_tick = time::Instant::now();
+        let mut duration = tick_duration(tempo);
 
-            if clock_enabled && tick_counter < CLOCK_MULTIPLIER {
-                clock_send
-                    .send(tick_counter)
-                    .expect("clock_multiplier send tick failed");
-                tick_counter += 1;
-                if tick_counter < CLOCK_MULTIPLIER {
-                    spin_sleep::sleep(tick_duration);
-                } else {
-                    // Short sleep before polling, waiting for clock to reset
-                    spin_sleep::sleep(time::Duration::from_micros(1));
+        loop {
+            stats.send_due();
+            let tick_timer = if running {
+                after(next_tick.saturating_duration_since(time::Instant::now()).saturating_sub(spin_margin))
+            } else {
+                never()
+            };
+
+            select! {
+                recv(clock_active) -> msg => {
+                    let active = msg.expect("internal_clock clock_active closed");
+                    if active && !running {
+                        tick_counter = 0;
+                        next_tick = time::Instant::now();
+                    }
+                    running = active;
+                }
+                recv(tempo_recv) -> msg => {
+                    duration = tick_duration(msg.expect("internal_clock tempo_recv closed"));
+                }
+                recv(tick_timer) -> _ => {
+                    spin_sleep::sleep(next_tick.saturating_duration_since(time::Instant::now()));
+                    let now = time::Instant::now();
+                    stats.stats.tick_latency.record(now.saturating_duration_since(next_tick));
+                    clock_send
+                        .send((tick_counter % CLOCK_MULTIPLIER, next_tick))
+                        .expect("internal_clock send tick failed");
+                    tick_counter += 1;
+                    next_tick += duration;
+                    if next_tick + duration * (CLOCK_MULTIPLIER as u32) < now {
+                        next_tick = now;
+                    }
                 }
             }
         }
@@ -95,45 +162,97 @@ pub fn start_clock_multiplier(
 
 // Main Controller ---------------------------------------------------------------------------------
 
-pub fn start_controller(context: &Context) {
+/// Starts all threads of the sequencer and returns the one handling MIDI input, which runs until
+/// the process exits.
+pub fn start_controller(context: &Context) -> thread::JoinHandle<()> {
     let debug = context.debug;
 
-    let (midi_clock_send, midi_clock_recv): (Sender<u64>, Receiver<u64>) = channel();
-    let (midi_state_send, midi_state_recv): (Sender<bool>, Receiver<bool>) = channel();
-    let (clock_reset_send, clock_reset_recv): (Sender<bool>, Receiver<bool>) = channel();
-    let (mult_clock_send, mult_clock_recv): (Sender<u64>, Receiver<u64>) = channel();
-    let (ctrl_updated_send, ctrl_updated_recv): (Sender<Controller>, Receiver<Controller>) =
-        channel();
-
-    let midi_recv = start_midi_listener();
-
-    start_clock_multiplier(midi_clock_recv, midi_state_recv, mult_clock_send);
-
-    let mut ctrl_def: Controller = start_performance(
+    let (midi_clock_send, midi_clock_recv) = unbounded::<(time::Instant, time::Instant)>();
+    let (midi_state_send, midi_state_recv): (Sender<bool>, Receiver<bool>) = unbounded();
+    let (clock_reset_send, clock_reset_recv): (Sender<bool>, Receiver<bool>) = unbounded();
+    let (mult_clock_send, mult_clock_recv) = unbounded::<(u64, time::Instant)>();
+    let (perf_updated_send, perf_updated_recv): (Sender<Performance>, Receiver<Performance>) =
+        unbounded();
+    let (action_send, action_recv) = unbounded::<(ControlAction, Quantize)>();
+
+    let scan_interval = match context.device_scan_interval {
+        0 => None,
+        ms => Some(time::Duration::from_millis(ms)),
+    };
+    let (midi_inputs_send, midi_inputs_recv): (Sender<Vec<MidiInput>>, Receiver<Vec<MidiInput>>) =
+        unbounded();
+    let midi_recv = start_midi_listener(scan_interval, midi_inputs_recv);
+
+    let stats_send = if context.stats { Some(start_stats_reporter()) } else { None };
+    let (tempo_send, tempo_recv): (Sender<f64>, Receiver<f64>) = unbounded();
+
+    let perf_def: Performance = start_performance(
         context,
-        clock_reset_recv,
-        mult_clock_recv,
-        ctrl_updated_send,
+        PerformanceChannels {
+            clock_reset_recv,
+            mult_clock_recv,
+            perf_updated: perf_updated_send,
+            midi_inputs: midi_inputs_send,
+            action_recv,
+            tempo_send,
+            stats_send: stats_send.clone(),
+        },
     );
 
+    let internal_clock = perf_def.controller.clock == Some(ClockSource::Internal);
+    if internal_clock {
+        let tempo = perf_def.controller.tempo.unwrap_or(DEFAULT_TEMPO_BPM as f64);
+        log::info(format!("INTERNAL CLOCK {:.1} BPM", tempo), 0);
+        start_internal_clock(tempo, tempo_recv, midi_state_recv, mult_clock_send, stats_send.clone());
+    } else {
+        start_clock_multiplier(midi_clock_recv, midi_state_recv, mult_clock_send, stats_send.clone());
+    }
+    let mut ctrl_def: Controller = perf_def.controller.clone();
+    let mut control_def: Vec<ControlMapping> = perf_def.control.unwrap_or_default();
+    let (routes_send, routes_recv): (Sender<Vec<Route>>, Receiver<Vec<Route>>) = unbounded();
+    let route_send = start_router(perf_def.routes.unwrap_or_default(), routes_recv);
+
     thread::spawn(move || {
         let mut clock_count = 0;
         let mut beat_count = 0;
         let mut bar_count = 1;
-        let mut last_tick = log::now_millis();
-        let mut tick_duration_history: [f64; 48] = [0.0; 48];
+        let mut tempo = TempoFollower::new(time::Duration::from_micros(
+            60_000_000 / (DEFAULT_TEMPO_BPM * DEFAULT_PARTS_PER_QUARTER),
+        ));
         let mut clock_start_time = log::now_millis();
+        let mut last_clock: Option<time::Instant> = None;
+        let mut stats = StatsCollector::new(stats_send);
 
         loop {
-            let (device, events) = midi_recv.recv().unwrap();
+            stats.send_due();
+
+            let (device, events) = select! {
+                recv(perf_updated_recv) -> msg => {
+                    log::success("UPDATE".to_string(), log::now_millis() - clock_start_time);
+                    let perf_def = msg.expect("perf_updated closed");
+                    ctrl_def = perf_def.controller;
+                    control_def = perf_def.control.unwrap_or_default();
+                    routes_send.send(perf_def.routes.unwrap_or_default()).expect("routes_send failed");
+                    continue;
+                }
+                recv(midi_recv) -> msg => msg.expect("midi listener stopped"),
+            };
 
-            let ctrl_updated_msg = ctrl_updated_recv.try_recv();
-            if ctrl_updated_msg.is_ok() {
-                log::success("UPDATE".to_string(), log::now_millis() - clock_start_time);
-                ctrl_def = ctrl_updated_msg.unwrap();
+            let device_name = device.name().to_string();
+            // Only channel messages are routed, the clock is handled here without waiting for them
+            let routed = events.iter().filter(|e| is_channel_message(e.message.status)).cloned().collect::<Vec<_>>();
+            if !routed.is_empty() {
+                route_send.send((device_name.to_string(), routed)).expect("route_send failed");
+            }
+
+            for event in &events {
+                for mapping in control_def.iter().filter(|m| mapping_matches(m, &device_name, event.message)) {
+                    action_send
+                        .send((mapping.action.clone(), mapping_quantize(mapping)))
+                        .expect("action_send failed");
+                }
             }
 
-            let device_name = device.name().to_string();
             for event in events {
                 if debug {
                     println!("[{}] {:?}", device, event);
@@ -142,25 +261,26 @@ pub fn start_controller(context: &Context) {
 
                 if device_name == ctrl_def.device {
                     // Global messages
-                    if message.status == 248 {
+                    if message.status == 248 && !internal_clock {
                         // Timing Clock
                         let mut ppq = DEFAULT_PARTS_PER_QUARTER as usize;
                         if ctrl_def.ppq.is_some() {
                             ppq = ctrl_def.ppq.unwrap() as usize;
                         }
 
-                        let tick = log::now_millis();
-                        let tick_elapsed = (tick - last_tick) as f64;
-                        last_tick = tick;
-                        tick_duration_history[clock_count % tick_duration_history.len()] =
-                            tick_elapsed;
+                        let now = time::Instant::now();
+                        if let Some(last_clock) = last_clock {
+                            stats.stats.clock_interval.record(now - last_clock);
+                        }
+                        last_clock = Some(now);
 
-                        let avg_dur_ms = average(&tick_duration_history);
+                        let next_clock = tempo.clock(now);
+                        if tempo.was_outlier() {
+                            stats.stats.clock_outliers += 1;
+                        }
 
                         if clock_count % ppq == 0 {
-                            let ms_per_beat = avg_dur_ms * (ppq as f64);
-                            let ms_per_min = 60.0 * 1000.0;
-                            let bpm = ms_per_min / ms_per_beat;
+                            let bpm = tempo.bpm(ppq as u64);
 
                             beat_count += 1;
 
@@ -180,7 +300,7 @@ pub fn start_controller(context: &Context) {
                         }
 
                         midi_clock_send
-                            .send((avg_dur_ms * 1000.0) as u64)
+                            .send((now, next_clock))
                             .expect("midi_clock_send failed");
 
                         clock_count += 1;
@@ -189,9 +309,11 @@ pub fn start_controller(context: &Context) {
                         beat_count = 0;
                         bar_count = 1;
                         clock_count = 0;
-                        last_tick = log::now_millis();
+                        last_clock = None;
+                        tempo.reset();
                         clock_start_time = log::now_millis();
                         log::event("START".to_string(), log::now_millis() - clock_start_time);
+                        midi_state_send.send(true).expect("midi_state_send failed");
                         clock_reset_send
                             .send(true)
                             .expect("clock_reset_send failed");
@@ -199,6 +321,8 @@ pub fn start_controller(context: &Context) {
                         // Stop
                         clock_start_time = log::now_millis();
                         log::event("STOP".to_string(), log::now_millis() - clock_start_time);
+                        last_clock = None;
+                        tempo.reset();
                         midi_state_send.send(false).expect("midi_state_send failed");
                         clock_reset_send
                             .send(true)
@@ -207,5 +331,5 @@ pub fn start_controller(context: &Context) {
                 }
             }
         }
-    });
+    })
 }
@@ -0,0 +1,222 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fmt;
+
+use crate::models::{Instrument, Performance, Scene, Sequence, Track};
+
+// Performance Diff --------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DiffEntry {
+    pub kind: &'static str,
+    pub name: String,
+    pub change: Change,
+}
+
+impl fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match self.change {
+            Change::Added => "+",
+            Change::Removed => "-",
+            Change::Modified => "~",
+        };
+        if !self.name.is_empty() {
+            write!(f, "{} {} \"{}\"", symbol, self.kind, self.name)
+        } else {
+            write!(f, "{} {}", symbol, self.kind)
+        }
+    }
+}
+
+/// Lists everything that was added, removed or modified between two performances. Tracks are
+/// named `scene/number:instrument`, counting from 1, and sequences `instrument/sequence`.
+pub fn diff_performance(old: &Performance, new: &Performance) -> Vec<DiffEntry> {
+    let mut entries: Vec<DiffEntry> = Vec::new();
+
+    diff_value(&mut entries, "controller", &old.controller, &new.controller);
+    diff_value(&mut entries, "inputs", &old.inputs, &new.inputs);
+    diff_value(&mut entries, "routes", &old.routes, &new.routes);
+    diff_value(&mut entries, "control", &old.control, &new.control);
+    diff_value(&mut entries, "playlist", &old.playlist, &new.playlist);
+    diff_value(&mut entries, "sections", &old.sections, &new.sections);
+    diff_value(&mut entries, "end", &old.end, &new.end);
+
+    diff_named(
+        &mut entries,
+        "scene",
+        &old.scenes,
+        &new.scenes,
+        |s| s.name.to_string(),
+        |o, n| {
+            o.length != n.length || o.tempo != n.tempo || o.ramp != n.ramp || o.follow != n.follow || o.sysex != n.sysex
+        },
+    );
+    for new_scene in &new.scenes {
+        if let Some(old_scene) = old.find_scene(&new_scene.name) {
+            diff_scene(&mut entries, old_scene, new_scene);
+        }
+    }
+
+    diff_named(
+        &mut entries,
+        "instrument",
+        &old.instruments,
+        &new.instruments,
+        |i| i.name.to_string(),
+        |o, n| o.device != n.device || o.channel != n.channel || o.data != n.data,
+    );
+    for new_inst in &new.instruments {
+        if let Some(old_inst) = old.find_instrument(&new_inst.name) {
+            diff_instrument(&mut entries, old_inst, new_inst);
+        }
+    }
+
+    entries
+}
+
+fn diff_scene(entries: &mut Vec<DiffEntry>, old: &Scene, new: &Scene) {
+    // A scene can play an instrument on several tracks, so they are told apart by their position
+    let old_tracks = old.tracks.iter().enumerate().collect::<Vec<_>>();
+    let new_tracks = new.tracks.iter().enumerate().collect::<Vec<_>>();
+    let name = |(index, t): &(usize, &Track)| format!("{}/{}:{}", new.name, index + 1, t.instrument);
+    diff_named(entries, "track", &old_tracks, &new_tracks, name, |o, n| o.1 != n.1);
+}
+
+fn diff_instrument(entries: &mut Vec<DiffEntry>, old: &Instrument, new: &Instrument) {
+    let name = |s: &Sequence| format!("{}/{}", new.name, s.name);
+    diff_named(entries, "sequence", &old.sequences, &new.sequences, name, |o, n| o != n);
+}
+
+fn diff_value<T: PartialEq>(entries: &mut Vec<DiffEntry>, kind: &'static str, old: &T, new: &T) {
+    if old != new {
+        entries.push(DiffEntry {
+            kind,
+            name: String::new(),
+            change: Change::Modified,
+        });
+    }
+}
+
+fn diff_named<T, N, M>(entries: &mut Vec<DiffEntry>, kind: &'static str, old: &[T], new: &[T], name: N, modified: M)
+where
+    N: Fn(&T) -> String,
+    M: Fn(&T, &T) -> bool,
+{
+    for n in new {
+        match old.iter().find(|o| name(o) == name(n)) {
+            Some(o) => {
+                if modified(o, n) {
+                    entries.push(DiffEntry { kind, name: name(n), change: Change::Modified });
+                }
+            }
+            None => entries.push(DiffEntry { kind, name: name(n), change: Change::Added }),
+        }
+    }
+    for o in old.iter().filter(|o| !new.iter().any(|n| name(n) == name(o))) {
+        entries.push(DiffEntry { kind, name: name(o), change: Change::Removed });
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{diff_performance, Change, DiffEntry};
+    use crate::models::{Instrument, Performance, PlaylistEntry, Scene, Sequence, Track};
+
+    fn performance() -> Performance {
+        let mut perf = Performance::new();
+        perf.playlist = vec![PlaylistEntry::scene("intro")];
+        perf.scenes = vec![Scene {
+            tracks: vec![Track {
+                instrument: String::from("drum1"),
+                follow: None,
+                play: vec![String::from("A")],
+                fill: None,
+            }],
+            ..Scene::new("intro")
+        }];
+
+        let mut inst = Instrument::new();
+        inst.name = String::from("drum1");
+        let mut seq = Sequence::new();
+        seq.name = String::from("A");
+        inst.sequences = vec![seq];
+        perf.instruments = vec![inst];
+        perf
+    }
+
+    fn entry(kind: &'static str, name: &str, change: Change) -> DiffEntry {
+        DiffEntry { kind, name: String::from(name), change }
+    }
+
+    #[test]
+    fn test_diff_unchanged() {
+        assert_eq!(diff_performance(&performance(), &performance()), vec![]);
+    }
+
+    #[test]
+    fn test_diff_performance() {
+        let old = performance();
+        let mut new = performance();
+        new.playlist.push(PlaylistEntry::scene("verse"));
+        new.scenes.push(Scene::new("verse"));
+        new.scenes[0].tracks[0].play.push(String::from("B"));
+        new.instruments[0].sequences[0].name = String::from("B");
+
+        assert_eq!(
+            diff_performance(&old, &new),
+            vec![
+                entry("playlist", "", Change::Modified),
+                entry("scene", "verse", Change::Added),
+                entry("track", "intro/1:drum1", Change::Modified),
+                entry("sequence", "drum1/B", Change::Added),
+                entry("sequence", "drum1/A", Change::Removed),
+            ]
+        );
+        assert_eq!(format!("{}", entry("track", "intro/1:drum1", Change::Modified)), "~ track \"intro/1:drum1\"");
+    }
+
+    #[test]
+    fn test_diff_duplicate_instruments() {
+        let mut old = performance();
+        let mut track = old.scenes[0].tracks[0].clone();
+        track.play = vec![String::from("B")];
+        old.scenes[0].tracks.push(track);
+        let mut new = old.clone();
+        new.scenes[0].tracks[1].play.push(String::from("A"));
+        let first = new.scenes[0].tracks[0].clone();
+        new.scenes[0].tracks.push(first);
+
+        assert_eq!(
+            diff_performance(&old, &new),
+            vec![
+                entry("track", "intro/2:drum1", Change::Modified),
+                entry("track", "intro/3:drum1", Change::Added),
+            ]
+        );
+    }
+}
@@ -0,0 +1,395 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::config::CLOCK_MULTIPLIER;
+use crate::midi::{MidiRecorder, RecordedEvent};
+use crate::models::{ControlAction, Performance, Quantize};
+use crate::performance::PerformanceController;
+
+// Engine ------------------------------------------------------------------------------------------
+
+/// Plays a performance on an external clock without any MIDI devices. The clock runs at
+/// `TICKS_PER_BEAT` ticks per quarter note and every call returns the messages sent since the
+/// last one, stamped with the tick they were sent on.
+pub struct Engine {
+    perf_ctrl: PerformanceController<MidiRecorder>,
+    tick: u64,
+    tempo: f64,
+    tempo_changes: Vec<(u64, f64)>,
+}
+
+impl Engine {
+    /// Fails if the performance doesn't validate, see `Performance::validate`.
+    pub fn new(perf: Performance) -> Result<Engine, String> {
+        perf.validate()?;
+        let perf_ctrl = PerformanceController::new(perf, MidiRecorder::new());
+        let tempo = perf_ctrl.tempo();
+        Ok(Engine {
+            perf_ctrl,
+            tick: 0,
+            tempo,
+            tempo_changes: vec![(0, tempo)],
+        })
+    }
+
+    /// The number of ticks played since the engine was created.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// The tempo set by the controller and the scenes, see `TempoRamp`.
+    pub fn tempo(&self) -> f64 {
+        self.perf_ctrl.tempo()
+    }
+
+    /// Plays the next `ticks` ticks.
+    pub fn advance(&mut self, ticks: u64) -> Vec<RecordedEvent> {
+        for _ in 0..ticks {
+            self.perf_ctrl.output().tick = self.tick;
+            self.perf_ctrl.clock(self.tick % CLOCK_MULTIPLIER);
+            if self.perf_ctrl.tempo() != self.tempo {
+                self.tempo = self.perf_ctrl.tempo();
+                self.tempo_changes.push((self.tick, self.tempo));
+            }
+            self.tick += 1;
+        }
+        self.perf_ctrl.output().tick = self.tick;
+        self.take_events()
+    }
+
+    /// Stages a new definition, which is applied at the boundary set by its `controller.reload`.
+    pub fn update(&mut self, perf: Performance) -> Vec<RecordedEvent> {
+        self.perf_ctrl.stage_def(perf);
+        self.take_events()
+    }
+
+    /// Queues a control action until its quantization boundary.
+    pub fn action(&mut self, action: ControlAction, quantize: Quantize) -> Vec<RecordedEvent> {
+        self.perf_ctrl.queue_action(action, quantize);
+        self.take_events()
+    }
+
+    /// Releases all notes that are still held.
+    pub fn stop(&mut self) -> Vec<RecordedEvent> {
+        self.perf_ctrl.release_all();
+        self.take_events()
+    }
+
+    /// The ticks the tempo changed on since the last call, with the new tempo. The first call starts
+    /// with the tempo at tick 0.
+    pub fn take_tempo_changes(&mut self) -> Vec<(u64, f64)> {
+        self.tempo_changes.drain(..).collect()
+    }
+
+    fn take_events(&mut self) -> Vec<RecordedEvent> {
+        self.perf_ctrl.output().events.drain(..).collect()
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{TICKS_PER_BEAT, TICKS_PER_MEASURE};
+    use crate::engine::Engine;
+    use crate::models::{
+        ControlAction, Instrument, Performance, PlaylistEnd, PlaylistEntry, Quantize, Scene, Sequence, SequenceStep,
+        TempoRamp, Track,
+    };
+
+    fn performance() -> Performance {
+        let mut perf = Performance::new();
+        perf.playlist = vec![PlaylistEntry::scene("one")];
+        perf.scenes = vec![Scene {
+            tracks: vec![Track {
+                instrument: String::from("bass"),
+                follow: None,
+                play: vec![String::from("A")],
+                fill: None,
+            }],
+            ..Scene::new("one")
+        }];
+
+        let step = SequenceStep {
+            pitch: Some(vec![String::from("36")]),
+            ..SequenceStep::new()
+        };
+        let mut seq = Sequence::new();
+        seq.name = String::from("A");
+        seq.steps = vec![Some(step), None, None, None];
+
+        let mut inst = Instrument::new();
+        inst.name = String::from("bass");
+        inst.device = String::from("synth");
+        inst.sequences = vec![seq];
+        perf.instruments = vec![inst];
+        perf
+    }
+
+    #[test]
+    fn test_engine_advance() {
+        let mut engine = Engine::new(performance()).unwrap();
+
+        let events = engine.advance(TICKS_PER_BEAT);
+        assert_eq!(engine.tick(), TICKS_PER_BEAT);
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].tick, events[0].message.status & 0xF0), (0, 0x90));
+        assert_eq!(events[0].device, "synth");
+        assert_eq!(events[1].message.status & 0xF0, 0x80);
+
+        assert_eq!(engine.advance(TICKS_PER_MEASURE - TICKS_PER_BEAT).len(), 0);
+        let events = engine.advance(1);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].tick, TICKS_PER_MEASURE);
+        assert_eq!(engine.stop().len(), 1);
+    }
+
+    #[test]
+    fn test_engine_invalid() {
+        assert!(Engine::new(Performance::new()).is_err());
+
+        let mut perf = performance();
+        perf.instruments[0].channel = 0;
+        assert!(Engine::new(perf).is_err());
+    }
+
+    #[test]
+    fn test_engine_tempo() {
+        let mut perf = performance();
+        perf.controller.sync = Some(vec![String::from("clock")]);
+        perf.scenes[0].tracks[0].play = vec![String::from("A"); 3];
+        perf.scenes[0].tempo = Some(100.0);
+        perf.scenes[0].ramp = Some(TempoRamp { to: 140.0, bars: 2 });
+
+        let mut engine = Engine::new(perf).unwrap();
+        assert_eq!(engine.tempo(), 100.0);
+
+        let events = engine.advance(TICKS_PER_BEAT);
+        let sync = events.iter().filter(|e| e.device == "clock").map(|e| e.message.status).collect::<Vec<_>>();
+        assert_eq!(sync[0], 0xFA);
+        assert_eq!(sync[1..].to_vec(), vec![0xF8; 24]);
+
+        engine.advance(TICKS_PER_MEASURE - TICKS_PER_BEAT);
+        assert!(engine.tempo() > 119.0 && engine.tempo() < 120.0, "tempo {}", engine.tempo());
+        engine.advance(TICKS_PER_MEASURE);
+        assert_eq!(engine.tempo(), 140.0);
+    }
+
+    #[test]
+    fn test_engine_playlist_end() {
+        let note_ons = |engine: &mut Engine, ticks| {
+            let events = engine.advance(ticks);
+            events.iter().filter(|e| e.message.status & 0xF0 == 0x90).count()
+        };
+
+        let mut perf = performance();
+        perf.playlist = vec![PlaylistEntry::scene("one").repeat(2).bars(2)];
+        perf.end = Some(PlaylistEnd::Stop);
+        let mut engine = Engine::new(perf.clone()).unwrap();
+        assert_eq!(note_ons(&mut engine, 3 * TICKS_PER_MEASURE), 3);
+        assert_eq!(note_ons(&mut engine, 4 * TICKS_PER_MEASURE), 0);
+
+        perf.end = Some(PlaylistEnd::Hold);
+        let mut engine = Engine::new(perf).unwrap();
+        assert_eq!(note_ons(&mut engine, 8 * TICKS_PER_MEASURE), 8);
+    }
+
+    #[test]
+    fn test_engine_follow_actions() {
+        let yaml_text = r#"
+controller: { device: control, channel: 1 }
+playlist: [ one, two, three ]
+scenes:
+  - name: one
+    length: 2
+    tracks: [ { instrument: bass, play: [ A ] } ]
+    follow: [ { jump: three }, { action: next, weight: 0 } ]
+  - { name: two, length: 2, tracks: [ { instrument: bass, play: [ B ] } ] }
+  - { name: three, length: 2, tracks: [ { instrument: bass, play: [ C ] } ], follow: [ first ] }
+instruments:
+  - name: bass
+    device: synth
+    channel: 1
+    sequences:
+      - { name: A, steps: [ { pitch: [ "36" ] } ] }
+      - { name: B, steps: [ { pitch: [ "38" ] } ] }
+      - { name: C, steps: [ { pitch: [ "40" ] } ] }
+"#;
+        let mut engine = Engine::new(serde_yaml::from_str::<Performance>(yaml_text).unwrap()).unwrap();
+        let mut note = || {
+            let events = engine.advance(TICKS_PER_MEASURE);
+            events.iter().find(|e| e.message.status == 0x90).map(|e| e.message.data1)
+        };
+        let notes = (0..7).map(|_| note().unwrap()).collect::<Vec<_>>();
+        assert_eq!(notes, vec![36, 40, 40, 36, 36, 40, 40]);
+    }
+
+    #[test]
+    fn test_engine_fill() {
+        let step = |pitch: &str, fill: Option<bool>| {
+            Some(SequenceStep {
+                pitch: Some(vec![pitch.to_string()]),
+                fill,
+                ..SequenceStep::new()
+            })
+        };
+        let mut perf = performance();
+        perf.instruments[0].sequences[0].steps[1] = step("38", Some(true));
+        let mut fill = Sequence::new();
+        fill.name = String::from("F");
+        fill.steps = vec![step("40", None), step("41", None), step("42", Some(false)), None];
+        perf.instruments[0].sequences.push(fill);
+        perf.scenes[0].tracks[0].play = vec![String::from("A"); 4];
+        perf.scenes[0].tracks[0].fill = Some(String::from("F"));
+
+        let mut engine = Engine::new(perf).unwrap();
+        let notes = |engine: &mut Engine| {
+            let events = engine.advance(TICKS_PER_MEASURE);
+            events.iter().filter(|e| e.message.status == 0x90).map(|e| e.message.data1).collect::<Vec<_>>()
+        };
+        assert_eq!(notes(&mut engine), vec![36]);
+        engine.action(ControlAction::Fill, Quantize::Bar);
+        assert_eq!(notes(&mut engine), vec![40, 41]);
+        assert_eq!(notes(&mut engine), vec![36]);
+
+        // Fills in the middle of a bar continue at the same position and end with the bar
+        engine.advance(TICKS_PER_BEAT);
+        engine.action(ControlAction::Fill, Quantize::Immediate);
+        assert_eq!(notes(&mut engine), vec![41, 36]);
+    }
+
+    #[test]
+    fn test_engine_fill_lengths() {
+        let engine = |pitches: &[u8]| {
+            let mut perf = performance();
+            let mut fill = Sequence::new();
+            fill.name = String::from("F");
+            fill.steps = pitches
+                .iter()
+                .map(|p| Some(SequenceStep { pitch: Some(vec![p.to_string()]), ..SequenceStep::new() }))
+                .collect();
+            perf.instruments[0].sequences.push(fill);
+            perf.scenes[0].tracks[0].play = vec![String::from("A"); 4];
+            perf.scenes[0].tracks[0].fill = Some(String::from("F"));
+            Engine::new(perf).unwrap()
+        };
+        let notes = |engine: &mut Engine, ticks| {
+            let events = engine.advance(ticks);
+            events.iter().filter(|e| e.message.status == 0x90).map(|e| e.message.data1).collect::<Vec<_>>()
+        };
+
+        // A longer fill picks up at the step that is due at the same position in the bar
+        let mut long = engine(&[50, 51, 52, 53, 54, 55, 56, 57]);
+        long.advance(TICKS_PER_BEAT);
+        long.action(ControlAction::Fill, Quantize::Immediate);
+        assert_eq!(notes(&mut long, TICKS_PER_MEASURE - TICKS_PER_BEAT), vec![52, 53, 54, 55, 56, 57]);
+        assert_eq!(notes(&mut long, TICKS_PER_MEASURE), vec![36]);
+
+        // A shorter fill started between its steps waits for its next step
+        let mut short = engine(&[60, 61]);
+        short.advance(TICKS_PER_BEAT + 1);
+        short.action(ControlAction::Fill, Quantize::Immediate);
+        assert_eq!(notes(&mut short, TICKS_PER_MEASURE - TICKS_PER_BEAT - 1), vec![61]);
+    }
+
+    #[test]
+    fn test_engine_follow_modes() {
+        let yaml_text = r#"
+controller: { device: control, channel: 1 }
+playlist: [ one ]
+scenes:
+  - name: one
+    length: 4
+    tracks:
+      - { instrument: lead, follow: bass, play: [ L1, L2 ] }
+      - { instrument: drums, play: [ K, K ] }
+      - { instrument: bass, follow: { instrument: drums, every: 2 }, play: [ B1, B2, B3 ] }
+      - { instrument: hat, follow: { instrument: drums, pitch: D2 }, play: [ H1, H2 ] }
+      - { instrument: perc, follow: { instrument: drums, reset: true }, play: [ P1, P2, P3 ] }
+instruments:
+  - name: drums
+    device: drums
+    channel: 10
+    sequences: [ { name: K, steps: [ { pitch: [ C2 ] }, { pitch: [ D2 ] }, { pitch: [ C2 ] }, { pitch: [ D2 ] } ] } ]
+  - name: bass
+    device: bass
+    channel: 1
+    sequences: [ { name: B1, steps: [ { pitch: [ "40" ] } ] }, { name: B2, steps: [ { pitch: [ "41" ] } ] },
+                 { name: B3, steps: [ { pitch: [ "42" ] } ] } ]
+  - name: lead
+    device: lead
+    channel: 2
+    sequences: [ { name: L1, steps: [ { pitch: [ "50" ] } ] }, { name: L2, steps: [ { pitch: [ "51" ] } ] } ]
+  - name: hat
+    device: hat
+    channel: 3
+    sequences: [ { name: H1, steps: [ { pitch: [ "60" ] } ] }, { name: H2, steps: [ { pitch: [ "61" ] } ] } ]
+  - name: perc
+    device: perc
+    channel: 4
+    sequences: [ { name: P1, steps: [ { pitch: [ "70" ] } ] }, { name: P2, steps: [ { pitch: [ "71" ] } ] },
+                 { name: P3, steps: [ { pitch: [ "72" ] } ] } ]
+"#;
+        let mut engine = Engine::new(serde_yaml::from_str::<Performance>(yaml_text).unwrap()).unwrap();
+        let events = engine.advance(TICKS_PER_MEASURE + 1);
+        let notes = |device: &str| {
+            let note_ons = events.iter().filter(|e| e.device == device && e.message.status & 0xF0 == 0x90);
+            note_ons.map(|e| (e.tick / TICKS_PER_BEAT, e.message.data1)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(notes("bass"), vec![(0, 40), (1, 41), (3, 42)]);
+        // The lead follows the bass although it comes first in the scene
+        assert_eq!(notes("lead"), vec![(0, 51), (1, 50), (3, 51)]);
+        assert_eq!(notes("hat"), vec![(0, 60), (1, 61), (3, 60)]);
+        // The drums start their sequences over in the second bar
+        assert_eq!(notes("perc"), vec![(0, 71), (1, 72), (2, 70), (3, 71), (4, 70)]);
+    }
+
+    #[test]
+    fn test_engine_sysex() {
+        let yaml_text = r#"
+controller: { device: control, channel: 1 }
+playlist: [ one ]
+scenes:
+  - name: one
+    sysex: [ { data: "F0 7E 7F 09 01 F7", device: synth } ]
+    tracks: [ { instrument: bass, play: [ A, A ] } ]
+instruments:
+  - name: bass
+    device: synth
+    channel: 3
+    sequences:
+      - name: A
+        steps:
+          - { pitch: [ "36" ], sysex: [ { data: "F0 43 1{channel} 4C {0} F7", values: [ 5 ] } ] }
+          - { sysex: [ { data: "F0 41 {channel} F7", device: fx, channel: 10 } ] }
+"#;
+        let mut engine = Engine::new(serde_yaml::from_str::<Performance>(yaml_text).unwrap()).unwrap();
+        let events = engine.advance(TICKS_PER_MEASURE);
+        let sysex = events.iter().filter_map(|e| e.sysex.clone().map(|s| (e.device.as_str(), s))).collect::<Vec<_>>();
+
+        assert_eq!(sysex, vec![
+            ("synth", vec![0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]),
+            ("synth", vec![0xF0, 0x43, 0x12, 0x4C, 0x05, 0xF7]),
+            ("fx", vec![0xF0, 0x41, 0x09, 0xF7]),
+        ]);
+        // The SysEx of a step goes out before its notes
+        let first_note = events.iter().position(|e| e.message.status & 0xF0 == 0x90).unwrap();
+        assert!(events[1].sysex.is_some() && first_note == 2);
+    }
+}
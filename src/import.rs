@@ -0,0 +1,277 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::fs;
+
+use crate::log;
+use crate::models::{Instrument, ModDevice, Performance, PlaylistEntry, Scene, Sequence, SequenceStep, Track};
+use crate::smf::{read_smf, SmfTrack};
+
+// Import ------------------------------------------------------------------------------------------
+
+pub struct ImportOptions {
+    pub steps_per_bar: usize,
+    pub bars_per_sequence: usize,
+    pub device: Option<String>,
+}
+
+/// Converts every channel used by a track into an instrument. Notes, velocities and program changes
+/// are quantized to the nearest step, note lengths are dropped since steps always release their
+/// notes halfway. Each controller number becomes a data lane that holds its last value and is sent
+/// on the steps where any controller changed. A single scene plays all sequences in order, the
+/// controller device is left empty.
+pub fn import_tracks(ticks_per_quarter: u16, tracks: &Vec<SmfTrack>, options: &ImportOptions) -> Performance {
+    let mut scene = Scene::new("import");
+    let mut perf = Performance::new();
+
+    let ticks_per_step = ticks_per_quarter as f64 * 4.0 / options.steps_per_bar as f64;
+    let steps_per_sequence = options.steps_per_bar * options.bars_per_sequence;
+
+    for track in tracks {
+        for channel in 0..16u8 {
+            let events = track
+                .events
+                .iter()
+                .filter(|(_, e)| e[0] & 0x0F == channel)
+                .map(|(tick, e)| ((*tick as f64 / ticks_per_step).round() as usize, e))
+                .collect::<Vec<_>>();
+            if events.is_empty() {
+                continue;
+            }
+
+            let step_count = events.iter().map(|(step, _)| step + 1).max().unwrap_or(0);
+            let sequence_count = step_count.div_ceil(steps_per_sequence);
+            let mut steps: Vec<Option<SequenceStep>> = vec![None; sequence_count * steps_per_sequence];
+            let mut controls: Vec<u8> = Vec::new();
+            let mut control_changes: Vec<Vec<(usize, u8)>> = vec![Vec::new(); steps.len()];
+
+            for (step_index, event) in &events {
+                let step_index = *step_index;
+                match event[0] & 0xF0 {
+                    0x90 if event[2] > 0 => {
+                        let step = steps[step_index].get_or_insert_with(empty_step);
+                        let pitch = event[1].to_string();
+                        let pitches = step.pitch.get_or_insert_with(Vec::new);
+                        if !pitches.contains(&pitch) {
+                            pitches.push(pitch);
+                        }
+                        let velocity = step.velocity.as_ref().map_or(0, |v| v.parse::<u8>().unwrap_or(0));
+                        step.velocity = Some(velocity.max(event[2]).to_string());
+                    }
+                    0xC0 => {
+                        steps[step_index].get_or_insert_with(empty_step).program = Some(event[1]);
+                    }
+                    0xB0 => {
+                        let lane = match controls.iter().position(|c| *c == event[1]) {
+                            Some(lane) => lane,
+                            None => {
+                                controls.push(event[1]);
+                                controls.len() - 1
+                            }
+                        };
+                        control_changes[step_index].push((lane, event[2]));
+                    }
+                    _ => {}
+                }
+            }
+
+            // Lanes start at the first value they ever take, so early steps don't jump to zero
+            let mut values: Vec<u8> = controls
+                .iter()
+                .enumerate()
+                .map(|(lane, _)| {
+                    control_changes
+                        .iter()
+                        .flatten()
+                        .find(|(l, _)| *l == lane)
+                        .map_or(0, |(_, value)| *value)
+                })
+                .collect();
+            for (step_index, changes) in control_changes.iter().enumerate() {
+                if !changes.is_empty() {
+                    for (lane, value) in changes {
+                        values[*lane] = *value;
+                    }
+                    steps[step_index].get_or_insert_with(empty_step).data = Some(values.to_vec());
+                }
+            }
+
+            let name = if track.events.iter().all(|(_, e)| e[0] & 0x0F == channel) {
+                track.name.to_string()
+            } else {
+                format!("{}-ch{}", track.name, channel + 1)
+            };
+            let device = options.device.as_ref().unwrap_or(&track.name).to_string();
+
+            let mut instrument = Instrument::new();
+            instrument.name = name.to_string();
+            instrument.device = device.to_string();
+            instrument.channel = channel + 1;
+            if !controls.is_empty() {
+                instrument.data = Some(
+                    controls
+                        .iter()
+                        .map(|control| ModDevice {
+                            device: device.to_string(),
+                            channel: channel + 1,
+                            control: *control,
+                        })
+                        .collect(),
+                );
+            }
+
+            let mut play: Vec<String> = Vec::new();
+            for (index, chunk) in steps.chunks(steps_per_sequence).enumerate() {
+                let mut sequence = Sequence::new();
+                sequence.name = format!("seq{}", index + 1);
+                sequence.steps = chunk.to_vec();
+                play.push(sequence.name.to_string());
+                instrument.sequences.push(sequence);
+            }
+
+            scene.tracks.push(Track {
+                instrument: name,
+                follow: None,
+                play,
+                fill: None,
+            });
+            perf.instruments.push(instrument);
+        }
+    }
+
+    perf.playlist.push(PlaylistEntry::scene(&scene.name));
+    perf.scenes.push(scene);
+    perf
+}
+
+fn empty_step() -> SequenceStep {
+    SequenceStep::new()
+}
+
+/// Imports a Standard MIDI File and writes the YAML to `yaml_file`, or prints it if none is given.
+pub fn import_file(midi_file: &String, yaml_file: &String, options: &ImportOptions) -> Result<(), std::io::Error> {
+    let bytes = fs::read(midi_file)?;
+    let (ticks_per_quarter, tracks) =
+        read_smf(&bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    let imported = import_tracks(ticks_per_quarter, &tracks, options);
+    // Write nothing that would fail to load
+    imported.validate().map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("imported performance is invalid: {}", e))
+    })?;
+    let yaml_text = serde_yaml::to_string(&imported).map_err(std::io::Error::other)?;
+
+    if !yaml_file.is_empty() {
+        fs::write(yaml_file, yaml_text)?;
+        log::success(
+            format!("IMPORTED {} instrument(s) to {:?}", imported.instruments.len(), yaml_file),
+            0,
+        );
+    } else {
+        println!("{}", yaml_text);
+    }
+    Ok(())
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::import::{import_tracks, ImportOptions};
+    use crate::smf::SmfTrack;
+
+    fn options(steps_per_bar: usize, bars_per_sequence: usize) -> ImportOptions {
+        ImportOptions {
+            steps_per_bar,
+            bars_per_sequence,
+            device: Some(String::from("synth")),
+        }
+    }
+
+    #[test]
+    fn test_import_notes() {
+        let mut track = SmfTrack::new("lead");
+        // 96 ticks per quarter note, so 96 ticks per step with 4 steps per bar
+        track.events.push((0, vec![0x92, 60, 90]));
+        track.events.push((2, vec![0x92, 64, 100]));
+        track.events.push((0, vec![0xC2, 7]));
+        track.events.push((90, vec![0x82, 60, 0]));
+        track.events.push((480, vec![0x92, 62, 80]));
+
+        let imported = import_tracks(96, &vec![track], &options(4, 1));
+        assert_eq!(imported.instruments.len(), 1);
+
+        let inst = &imported.instruments[0];
+        assert_eq!(inst.name, "lead");
+        assert_eq!(inst.device, "synth");
+        assert_eq!(inst.channel, 3);
+        assert_eq!(inst.sequences.len(), 2);
+
+        let first = inst.sequences[0].steps[0].as_ref().unwrap();
+        assert_eq!(first.pitch, Some(vec![String::from("60"), String::from("64")]));
+        assert_eq!(first.velocity, Some(String::from("100")));
+        assert_eq!(first.program, Some(7));
+        assert!(inst.sequences[0].steps[1].is_none());
+        assert_eq!(inst.sequences[1].steps[1].as_ref().unwrap().pitch, Some(vec![String::from("62")]));
+
+        assert_eq!(imported.scenes[0].tracks[0].play, vec![String::from("seq1"), String::from("seq2")]);
+    }
+
+    #[test]
+    fn test_import_controls() {
+        let mut track = SmfTrack::new("pad");
+        track.events.push((96, vec![0xB0, 74, 20]));
+        track.events.push((192, vec![0xB0, 71, 50]));
+        track.events.push((192, vec![0xB0, 74, 30]));
+        track.events.push((384, vec![0x91, 48, 100]));
+
+        let imported = import_tracks(96, &vec![track], &options(4, 2));
+        assert_eq!(imported.instruments.len(), 2);
+        assert_eq!(imported.instruments[0].name, "pad-ch1");
+        assert_eq!(imported.instruments[1].name, "pad-ch2");
+
+        let inst = &imported.instruments[0];
+        let controls = inst.data.as_ref().unwrap().iter().map(|d| d.control).collect::<Vec<_>>();
+        assert_eq!(controls, vec![74, 71]);
+        assert_eq!(inst.sequences.len(), 1);
+        assert_eq!(inst.sequences[0].steps.len(), 8);
+        assert!(inst.sequences[0].steps[0].is_none());
+        assert_eq!(inst.sequences[0].steps[1].as_ref().unwrap().data, Some(vec![20, 50]));
+        assert_eq!(inst.sequences[0].steps[2].as_ref().unwrap().data, Some(vec![30, 50]));
+    }
+
+    #[test]
+    fn test_import_validation() {
+        let note = |name: &str| {
+            let mut track = SmfTrack::new(name);
+            track.events.push((0, vec![0x90, 60, 100]));
+            track
+        };
+        assert!(import_tracks(96, &vec![note("lead")], &options(4, 1)).validate().is_ok());
+
+        // No channel events leave the scene without tracks
+        let empty = import_tracks(96, &vec![SmfTrack::new("tempo")], &options(4, 1)).validate();
+        assert_eq!(empty, Err(String::from("scene \"import\" has no tracks")));
+
+        let long = import_tracks(96, &vec![note("lead")], &options(64, 64)).validate();
+        assert!(long.unwrap_err().contains("has 4096 steps"));
+
+        let duplicate = import_tracks(96, &vec![note("lead"), note("lead")], &options(4, 1)).validate();
+        assert_eq!(duplicate, Err(String::from("instrument \"lead\" is defined more than once")));
+    }
+}
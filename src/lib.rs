@@ -0,0 +1,77 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! The cfgseq sequencing engine. A `Performance` is loaded from YAML or built in code, and an
+//! `Engine` plays it on an external clock and returns the MIDI messages it sends:
+//!
+//! ```no_run
+//! use std::ffi::OsStr;
+//! use cfgseq::{load_performance_file, Engine, TICKS_PER_BEAT};
+//!
+//! let perf = load_performance_file(OsStr::new("data/example.yaml")).unwrap();
+//! let mut engine = Engine::new(perf).unwrap();
+//! for event in engine.advance(TICKS_PER_BEAT) {
+//!     println!("{} {} {:?}", event.tick, event.device, event.message);
+//! }
+//! ```
+//!
+//! The `cfgseq` binary runs the same engine in real time on MIDI devices.
+
+pub mod builder;
+pub(crate) mod config;
+pub(crate) mod context;
+pub(crate) mod control;
+pub(crate) mod controller;
+pub(crate) mod diff;
+pub mod engine;
+pub(crate) mod import;
+pub(crate) mod log;
+pub(crate) mod midi;
+pub mod models;
+pub(crate) mod performance;
+pub(crate) mod performance_file;
+pub(crate) mod plan;
+pub(crate) mod random;
+pub(crate) mod render;
+pub(crate) mod router;
+pub(crate) mod sequence_player;
+pub(crate) mod smf;
+pub(crate) mod stats;
+pub(crate) mod tempo;
+
+pub use crate::builder::{
+    InstrumentBuilder, PerformanceBuilder, SceneBuilder, SequenceBuilder, StepBuilder, TrackBuilder,
+};
+pub use crate::config::{TICKS_PER_BEAT, TICKS_PER_MEASURE};
+pub use crate::engine::Engine;
+pub use crate::midi::RecordedEvent;
+pub use crate::models::{ControlAction, Performance, Quantize};
+pub use crate::performance_file::load_performance_file;
+pub use portmidi::MidiMessage;
+
+/// Entry points of the `cfgseq` binary, which are not part of the library API.
+#[doc(hidden)]
+pub mod cli {
+    pub use crate::config::{PROJECT_NAME, VERSION};
+    pub use crate::context::Context;
+    pub use crate::controller::start_controller;
+    pub use crate::import::{import_file, ImportOptions};
+    pub use crate::midi::list_midi_devices;
+    pub use crate::render::render_file;
+}
@@ -56,6 +56,14 @@ pub fn event(text: String, timestamp: u128) {
     info(Color::Purple.paint(text).to_string(), timestamp);
 }
 
+pub fn warning(text: String, timestamp: u128) {
+    info(Color::Yellow.paint(text).to_string(), timestamp);
+}
+
+pub fn error(text: String, timestamp: u128) {
+    info(Color::Red.bold().paint(text).to_string(), timestamp);
+}
+
 pub fn success(text: String, timestamp: u128) {
     info(Color::Green.paint(text).to_string(), timestamp);
 }
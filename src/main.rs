@@ -16,26 +16,13 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use std::thread;
-use std::time::Duration;
+use std::process;
 
 use docopt::Docopt;
 use serde::Deserialize;
 
-use crate::config::{PROJECT_NAME, VERSION};
-use crate::context::Context;
-use crate::controller::start_controller;
-use crate::midi::list_midi_devices;
-
-mod config;
-mod context;
-mod controller;
-mod log;
-mod midi;
-mod models;
-mod performance;
-mod performance_file;
-mod sequence_player;
+use cfgseq::cli::{import_file, list_midi_devices, render_file, start_controller, Context, ImportOptions};
+use cfgseq::cli::{PROJECT_NAME, VERSION};
 
 // Options -----------------------------------------------------------------------------------------
 
@@ -44,20 +31,44 @@ CFG SEQ
 
 Usage:
   cfgseq list-devices
-  cfgseq [--performance=<perf_file>] [--debug]
+  cfgseq render <performance> --output=<file> [--bars=<n>] [--bpm=<bpm>]
+  cfgseq import <midi> [--output=<file>] [--steps=<n>] [--split=<bars>] [--device=<device>]
+  cfgseq [--performance=<perf_file>] [--device-scan=<ms>] [--strict] [--stats] [--debug]
   cfgseq (-h | --help)
 
 Options:
   -h --help                        Show this screen.
   -d --debug                       Enable debug features
   --performance=<perf_file>        Performance definition file.
+  --device-scan=<ms>               Interval for rescanning MIDI devices, 0 disables it.
+  --strict                         Exit if the performance file fails to load at startup.
+  --stats                          Log timing jitter and latency percentiles every 10 seconds.
+  -o --output=<file>               File written by render or import, import prints to stdout without it.
+  --bars=<n>                       Number of bars to render [default: 16].
+  --bpm=<bpm>                      Tempo of the rendered file if the performance sets none [default: 120].
+  --steps=<n>                      Steps per bar of imported sequences [default: 16].
+  --split=<bars>                   Bars per imported sequence [default: 1].
+  --device=<device>                Output device of imported instruments, defaults to the track name.
 ";
 
 #[derive(Debug, Deserialize)]
 struct Args {
     flag_debug: bool,
+    flag_strict: bool,
+    flag_stats: bool,
     flag_performance: Vec<String>,
+    flag_device_scan: Option<u64>,
     cmd_list_devices: bool,
+    cmd_render: bool,
+    cmd_import: bool,
+    arg_performance: String,
+    arg_midi: String,
+    flag_output: String,
+    flag_bars: u64,
+    flag_bpm: f64,
+    flag_steps: usize,
+    flag_split: usize,
+    flag_device: Option<String>,
 }
 
 // Main --------------------------------------------------------------------------------------------
@@ -74,6 +85,21 @@ fn run() {
 
     if args.cmd_list_devices {
         list_midi_devices();
+    } else if args.cmd_render {
+        render_file(&args.arg_performance, &args.flag_output, args.flag_bars, args.flag_bpm).unwrap_or_else(|e| {
+            println!("Failed to render {}: {}", args.arg_performance, e);
+            process::exit(1);
+        });
+    } else if args.cmd_import {
+        let options = ImportOptions {
+            steps_per_bar: args.flag_steps.max(1),
+            bars_per_sequence: args.flag_split.max(1),
+            device: args.flag_device.to_owned(),
+        };
+        import_file(&args.arg_midi, &args.flag_output, &options).unwrap_or_else(|e| {
+            println!("Failed to import {}: {}", args.arg_midi, e);
+            process::exit(1);
+        });
     } else {
         start(&context_from_args(&args));
     }
@@ -83,6 +109,8 @@ fn context_from_args(args: &Args) -> Context {
     let mut context: Context = Context::new();
 
     context.debug = args.flag_debug;
+    context.strict = args.flag_strict;
+    context.stats = args.flag_stats;
     if context.debug {
         println!("{:?}", args);
     }
@@ -91,13 +119,15 @@ fn context_from_args(args: &Args) -> Context {
         context.performance = args.flag_performance[0].to_owned();
     }
 
+    if let Some(interval) = args.flag_device_scan {
+        context.device_scan_interval = interval;
+    }
+
     context
 }
 
 pub fn start(context: &Context) {
-    start_controller(context);
-
-    loop {
-        thread::sleep(Duration::from_millis(60_000));
+    if start_controller(context).join().is_err() {
+        process::exit(1);
     }
 }
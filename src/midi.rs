@@ -19,13 +19,20 @@
 extern crate portmidi;
 
 use portmidi::PortMidi;
-use portmidi::{Direction, MidiMessage};
-use std::sync::mpsc;
+use portmidi::{Direction, MidiMessage, OutputPort};
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use crossbeam_channel::{unbounded, Receiver};
 use spin_sleep;
 
+use crate::config::{MIDI_ACTIVE_WINDOW_MS, MIDI_POLL_ACTIVE_US, MIDI_POLL_IDLE_MS, OUTPUT_RETRY_MS};
+use crate::log;
+use crate::models::{MessageType, MidiInput};
+use crate::stats::TimingStats;
+
 // -------------------------------------------------------------------------------------------------
 
 const MIDI_BUFFER_SIZE: usize = 1024;
@@ -33,58 +40,222 @@ const VERBOSE_DEBUG: bool = false;
 
 // -------------------------------------------------------------------------------------------------
 
+/// A destination for the messages of a performance, addressed by device name.
+pub trait MidiOutput {
+    fn write_messages(&mut self, device_name: &str, messages: &[MidiMessage]);
+
+    /// Writes a whole SysEx message, from F0 to F7.
+    fn write_sysex(&mut self, device_name: &str, message: &[u8]);
+}
+
+/// What to write to an output port.
+enum Payload<'a> {
+    Messages(&'a [MidiMessage]),
+    SysEx(&'a [u8]),
+}
+
+/// Writes to the output ports of the PortMidi session, which are shared by all device managers.
 pub struct DeviceManager {
-    context: PortMidi,
+    /// Outputs whose last write failed, with the time they were last tried.
+    offline_outputs: HashMap<String, Instant>,
+    scheduled: Option<Instant>,
+    stats: TimingStats,
 }
 
 impl DeviceManager {
     pub fn new() -> DeviceManager {
         DeviceManager {
-            context: PortMidi::new().unwrap(),
+            offline_outputs: HashMap::new(),
+            scheduled: None,
+            stats: TimingStats::new(),
         }
     }
 
-    pub fn write_messages(&mut self, device_name: String, messages: Vec<MidiMessage>) {
-        match self.context.devices() {
-            Ok(devices) => {
-                for device_info in devices.into_iter().collect::<Vec<_>>() {
-                    if device_info.direction() == Direction::Output
-                        && device_info.name() == &device_name
-                    {
-                        match self.context.device(device_info.id()) {
-                            Ok(dev) => match self.context.output_port(dev, 1024) {
-                                Ok(mut output_port) => {
-                                    for message in &messages {
-                                        if VERBOSE_DEBUG { println!("{:?}", message); }
-                                        output_port
-                                            .write_message(*message)
-                                            .expect("midi write_message failed");
-                                    }
-                                }
-                                Err(e) => {
-                                    println!("Failed to open MIDI output port: {}", e);
-                                }
-                            },
-                            Err(e) => {
-                                println!("Failed to open MIDI device: {}", e);
-                            }
-                        }
-                    }
+    /// Sets the time the following writes were due, so that their latency is recorded. Writes
+    /// without a schedule, like MIDI thru, aren't measured.
+    pub fn schedule(&mut self, scheduled: Option<Instant>) {
+        self.scheduled = scheduled;
+    }
+
+    pub fn take_stats(&mut self) -> TimingStats {
+        std::mem::replace(&mut self.stats, TimingStats::new())
+    }
+
+    /// Writes to the port of the device, opening it if needed. A port whose write fails is closed, and
+    /// the output is reopened at most every `OUTPUT_RETRY_MS` until it is back, dropping the writes in
+    /// between.
+    fn write(&mut self, device_name: &str, payload: Payload) {
+        let retry = Duration::from_millis(OUTPUT_RETRY_MS);
+        if self.offline_outputs.get(device_name).is_some_and(|tried| tried.elapsed() < retry) {
+            return;
+        }
+
+        let mut session = session();
+        match session.write(device_name, payload) {
+            Ok(()) => {
+                if let Some(scheduled) = self.scheduled {
+                    self.stats.write_latency.record(Instant::now().saturating_duration_since(scheduled));
+                }
+                if self.offline_outputs.remove(device_name).is_some() {
+                    log::success(format!("OUTPUT ONLINE \"{}\"", device_name), 0);
                 }
             }
             Err(e) => {
-                println!("Failed to get MIDI devices: {}", e);
+                session.outputs.remove(device_name);
+                // Only report the first failure, the device stays offline until a write succeeds
+                if self.offline_outputs.insert(device_name.to_string(), Instant::now()).is_none() {
+                    log::warning(format!("OUTPUT OFFLINE \"{}\": {}", device_name, e), 0);
+                }
             }
         }
     }
 }
 
+impl MidiOutput for DeviceManager {
+    fn write_messages(&mut self, device_name: &str, messages: &[MidiMessage]) {
+        self.write(device_name, Payload::Messages(messages));
+    }
+
+    fn write_sysex(&mut self, device_name: &str, message: &[u8]) {
+        self.write(device_name, Payload::SysEx(message));
+    }
+}
+
+// MIDI Recorder -----------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedEvent {
+    pub tick: u64,
+    pub device: String,
+    /// For SysEx only the status byte F0.
+    pub message: MidiMessage,
+    pub sysex: Option<Vec<u8>>,
+}
+
+/// Records all messages together with the current `tick` instead of sending them to a device.
+pub struct MidiRecorder {
+    pub tick: u64,
+    pub events: Vec<RecordedEvent>,
+}
+
+impl MidiRecorder {
+    pub fn new() -> MidiRecorder {
+        MidiRecorder {
+            tick: 0,
+            events: Vec::new(),
+        }
+    }
+}
+
+impl Default for MidiRecorder {
+    fn default() -> MidiRecorder {
+        MidiRecorder::new()
+    }
+}
+
+impl MidiOutput for MidiRecorder {
+    fn write_messages(&mut self, device_name: &str, messages: &[MidiMessage]) {
+        for message in messages {
+            self.events.push(RecordedEvent {
+                tick: self.tick,
+                device: device_name.to_string(),
+                message: *message,
+                sysex: None,
+            });
+        }
+    }
+
+    fn write_sysex(&mut self, device_name: &str, message: &[u8]) {
+        self.events.push(RecordedEvent {
+            tick: self.tick,
+            device: device_name.to_string(),
+            message: MidiMessage {
+                status: 0xF0,
+                data1: 0,
+                data2: 0,
+                data3: 0,
+            },
+            sysex: Some(message.to_vec()),
+        });
+    }
+}
+
 // MIDI Devices ------------------------------------------------------------------------------------
 
-pub fn list_midi_devices() {
-    let context = portmidi::PortMidi::new().unwrap();
+/// PortMidi and the output ports opened on it, shared by the listener and all device managers.
+/// PortMidi only enumerates the devices when it's initialized, so finding devices connected since
+/// means re-initializing it, which invalidates all ports.
+struct Session {
+    context: Option<PortMidi>,
+    /// Output ports stay open until a write to them fails or PortMidi is re-initialized.
+    outputs: HashMap<String, OutputPort>,
+}
+
+impl Session {
+    fn context(&mut self) -> &PortMidi {
+        self.context.get_or_insert_with(|| PortMidi::new().expect("failed to initialize PortMidi"))
+    }
+
+    /// Closes all output ports and initializes PortMidi again. The listener closes its input ports
+    /// before, as they are invalid afterwards.
+    fn reinitialize(&mut self) {
+        self.outputs.clear();
+        // The old context has to be terminated before the new one enumerates the devices
+        self.context = None;
+        self.context();
+    }
+
+    fn open_output(&mut self, device_name: &str) -> Result<OutputPort, String> {
+        let context = self.context();
+        let device = context
+            .devices()
+            .map_err(|e| format!("failed to get MIDI devices: {}", e))?
+            .into_iter()
+            .find(|d| d.direction() == Direction::Output && d.name() == device_name)
+            .ok_or_else(|| String::from("device not found"))?;
+        context
+            .output_port(device, MIDI_BUFFER_SIZE)
+            .map_err(|e| format!("failed to open MIDI output port: {}", e))
+    }
+
+    fn write(&mut self, device_name: &str, payload: Payload) -> Result<(), String> {
+        if !self.outputs.contains_key(device_name) {
+            let port = self.open_output(device_name)?;
+            self.outputs.insert(device_name.to_string(), port);
+        }
+        let output_port = self.outputs.get_mut(device_name).ok_or_else(|| String::from("device not found"))?;
+        match payload {
+            Payload::Messages(messages) => {
+                for message in messages {
+                    if VERBOSE_DEBUG { println!("{:?}", message); }
+                    output_port
+                        .write_message(*message)
+                        .map_err(|e| format!("midi write_message failed: {}", e))?;
+                }
+            }
+            // PortMidi sends SysEx as a byte stream instead of packing it into messages
+            Payload::SysEx(message) => {
+                if VERBOSE_DEBUG { println!("{:02X?}", message); }
+                output_port
+                    .write_sysex(0, message)
+                    .map_err(|e| format!("midi write_sysex failed: {}", e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Locks the PortMidi session. Writes wait while the listener re-initializes PortMidi.
+fn session() -> MutexGuard<'static, Session> {
+    static SESSION: OnceLock<Mutex<Session>> = OnceLock::new();
+    SESSION
+        .get_or_init(|| Mutex::new(Session { context: None, outputs: HashMap::new() }))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
 
-    let devices = context.devices().unwrap().into_iter().collect::<Vec<_>>();
+pub fn list_midi_devices() {
+    let devices = session().context().devices().unwrap();
 
     println!("MIDI Devices:");
     for device in devices {
@@ -94,47 +265,237 @@ pub fn list_midi_devices() {
 
 // MIDI Listener -----------------------------------------------------------------------------------
 
-pub fn start_midi_listener(
-) -> mpsc::Receiver<(portmidi::DeviceInfo, std::vec::Vec<portmidi::MidiEvent>)> {
-    let midi_read_wait = Duration::from_micros(50);
-    let context = portmidi::PortMidi::new().unwrap();
-    let (tx, rx) = mpsc::channel();
-    thread::spawn(move || {
-        let in_ports = context
+/// A listed device, by direction and name.
+type Device = (Direction, String);
+
+/// The devices the listener lists and opens inputs on.
+trait DeviceSource {
+    type Port;
+
+    fn devices(&mut self) -> Vec<Device>;
+
+    fn open_input(&mut self, device_name: &str) -> Option<Self::Port>;
+
+    /// Enumerates the devices again, which invalidates all open ports.
+    fn reinitialize(&mut self);
+}
+
+/// The devices of the PortMidi session.
+struct PortMidiSource;
+
+impl DeviceSource for PortMidiSource {
+    type Port = portmidi::InputPort;
+
+    fn devices(&mut self) -> Vec<Device> {
+        session()
+            .context()
+            .devices()
+            .unwrap_or_default()
+            .iter()
+            .map(|dev| (dev.direction(), dev.name().to_string()))
+            .collect()
+    }
+
+    fn open_input(&mut self, device_name: &str) -> Option<portmidi::InputPort> {
+        let mut session = session();
+        let context = session.context();
+        let device = context
             .devices()
-            .unwrap()
+            .unwrap_or_default()
             .into_iter()
-            .filter_map(|dev| context.input_port(dev, MIDI_BUFFER_SIZE).ok())
-            .collect::<Vec<_>>();
+            .find(|dev| dev.direction() == Direction::Input && dev.name() == device_name)?;
+        context.input_port(device, MIDI_BUFFER_SIZE).ok()
+    }
+
+    fn reinitialize(&mut self) {
+        session().reinitialize();
+    }
+}
+
+/// Starts a thread that reads the MIDI inputs referenced by the performance and forwards their
+/// events. The set of inputs and their message filters is replaced whenever a new list arrives on
+/// `inputs_recv`.
+///
+/// Every `scan_interval` PortMidi is re-initialized to enumerate the devices again, and
+/// connects/disconnects are logged. This closes all ports: the inputs are reopened right away, the
+/// outputs on their next write, and writes wait until PortMidi is back. Messages that arrive while
+/// the inputs are closed are lost. A port that fails to read is closed and reopened by the next
+/// scan. A `scan_interval` of `None` disables periodic scanning.
+///
+/// PortMidi can't block on input, so the ports are polled: every `MIDI_POLL_ACTIVE_US` while
+/// messages keep arriving (e.g. a running clock), and every `MIDI_POLL_IDLE_MS` after none arrived
+/// for `MIDI_ACTIVE_WINDOW_MS`.
+pub fn start_midi_listener(
+    scan_interval: Option<Duration>,
+    inputs_recv: Receiver<Vec<MidiInput>>,
+) -> Receiver<(portmidi::DeviceInfo, std::vec::Vec<portmidi::MidiEvent>)> {
+    let active_wait = Duration::from_micros(MIDI_POLL_ACTIVE_US);
+    let idle_wait = Duration::from_millis(MIDI_POLL_IDLE_MS);
+    let active_window = Duration::from_millis(MIDI_ACTIVE_WINDOW_MS);
+    let (tx, rx) = unbounded();
+    thread::spawn(move || {
+        let mut source = PortMidiSource;
+        let mut inputs: Vec<MidiInput> = Vec::new();
+        let mut devices = source.devices();
+        let mut in_ports: Vec<(portmidi::InputPort, MidiInput)> = Vec::new();
+        let mut last_scan = Instant::now();
+        let mut last_event = Instant::now() - active_window;
         loop {
-            for port in &in_ports {
-                if let Ok(Some(events)) = port.read_n(MIDI_BUFFER_SIZE) {
-                    tx.send((port.device(), events)).unwrap();
+            if let Ok(new_inputs) = inputs_recv.try_recv() {
+                if new_inputs != inputs {
+                    inputs = new_inputs;
+                    update_input_ports(&mut source, &mut in_ports, &inputs, &devices);
+                }
+            }
+
+            let mut failed_ports: Vec<usize> = Vec::new();
+            for (index, (port, input)) in in_ports.iter().enumerate() {
+                match port.read_n(MIDI_BUFFER_SIZE) {
+                    Ok(Some(events)) => {
+                        let events = events
+                            .into_iter()
+                            .filter(|e| input.accepts(message_type(e.message.status)))
+                            .collect::<Vec<_>>();
+                        if !events.is_empty() {
+                            tx.send((port.device(), events)).unwrap();
+                            last_event = Instant::now();
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(_) => failed_ports.push(index),
                 }
             }
-            spin_sleep::sleep(midi_read_wait);
+            for index in failed_ports.into_iter().rev() {
+                let (_, input) = in_ports.remove(index);
+                log::warning(format!("INPUT FAILED \"{}\"", input.device), 0);
+            }
+
+            if scan_interval.is_some_and(|interval| last_scan.elapsed() >= interval) {
+                devices = rescan(&mut source, &mut in_ports, &inputs, &devices);
+                last_scan = Instant::now();
+            }
+
+            if last_event.elapsed() < active_window {
+                spin_sleep::sleep(active_wait);
+            } else {
+                thread::sleep(idle_wait);
+            }
         }
     });
     rx
 }
 
+/// Closes the ports of inputs that are no longer referenced or listed, updates the filters of the
+/// others and opens the referenced inputs that are listed but not open yet.
+fn update_input_ports<S: DeviceSource>(
+    source: &mut S,
+    ports: &mut Vec<(S::Port, MidiInput)>,
+    inputs: &[MidiInput],
+    devices: &[Device],
+) {
+    ports.retain(|(_, open)| inputs.iter().any(|i| i.device == open.device) && is_input(devices, &open.device));
+    for (_, open) in ports.iter_mut() {
+        if let Some(input) = inputs.iter().find(|i| i.device == open.device) {
+            *open = input.clone();
+        }
+    }
+
+    let open_devices = ports.iter().map(|(_, i)| i.device.to_string()).collect::<Vec<_>>();
+    for input in inputs_to_open(inputs, devices, &open_devices) {
+        if let Some(port) = source.open_input(&input.device) {
+            ports.push((port, input.clone()));
+        }
+    }
+}
+
+/// Closes the input ports, re-initializes the source and reopens the referenced inputs that are
+/// listed. Returns the devices listed now.
+fn rescan<S: DeviceSource>(
+    source: &mut S,
+    ports: &mut Vec<(S::Port, MidiInput)>,
+    inputs: &[MidiInput],
+    known: &[Device],
+) -> Vec<Device> {
+    ports.clear();
+    source.reinitialize();
+    let devices = source.devices();
+    log_device_changes(known, &devices);
+    update_input_ports(source, ports, inputs, &devices);
+    devices
+}
+
+/// The referenced inputs that are listed as devices but have no open port.
+fn inputs_to_open<'a>(
+    inputs: &'a [MidiInput],
+    devices: &[Device],
+    open_devices: &[String],
+) -> Vec<&'a MidiInput> {
+    inputs
+        .iter()
+        .filter(|i| is_input(devices, &i.device) && !open_devices.contains(&i.device))
+        .collect()
+}
+
+fn is_input(devices: &[Device], device_name: &str) -> bool {
+    devices.iter().any(|(direction, name)| *direction == Direction::Input && name == device_name)
+}
+
+/// The devices in `current` that aren't in `known`, and those in `known` that are gone.
+fn device_changes<'a>(known: &'a [Device], current: &'a [Device]) -> (Vec<&'a Device>, Vec<&'a Device>) {
+    let connected = current.iter().filter(|d| !known.contains(d)).collect();
+    let disconnected = known.iter().filter(|d| !current.contains(d)).collect();
+    (connected, disconnected)
+}
+
+fn log_device_changes(known: &[Device], current: &[Device]) {
+    let (connected, disconnected) = device_changes(known, current);
+    for (direction, name) in connected {
+        log::success(format!("CONNECT {:?} \"{}\"", direction, name), 0);
+    }
+    for (direction, name) in disconnected {
+        log::warning(format!("DISCONNECT {:?} \"{}\"", direction, name), 0);
+    }
+}
+
 // MIDI Messages -----------------------------------------------------------------------------------
 
-#[allow(dead_code)]
 pub fn is_channel_message(status_bytes: u8) -> bool {
     status_bytes >= 128 && status_bytes <= 239
 }
 
-#[allow(dead_code)]
 pub fn parse_channel(status_bytes: u8) -> u8 {
     status_bytes & 0b00001111
 }
 
-#[allow(dead_code)]
 pub fn parse_status(status_bytes: u8) -> u8 {
     status_bytes & 0b011110000
 }
 
+pub fn message_type(status_byte: u8) -> MessageType {
+    match status_byte {
+        0x80..=0x8F => MessageType::NoteOff,
+        0x90..=0x9F => MessageType::NoteOn,
+        0xA0..=0xAF => MessageType::PolyAftertouch,
+        0xB0..=0xBF => MessageType::ControlChange,
+        0xC0..=0xCF => MessageType::ProgramChange,
+        0xD0..=0xDF => MessageType::ChannelAftertouch,
+        0xE0..=0xEF => MessageType::PitchBend,
+        0xF1 => MessageType::TimeCode,
+        0xF2 => MessageType::SongPosition,
+        0xF3 => MessageType::SongSelect,
+        0xF6 => MessageType::TuneRequest,
+        0xF8 => MessageType::Clock,
+        0xFA => MessageType::Start,
+        0xFB => MessageType::Continue,
+        0xFC => MessageType::Stop,
+        0xFE => MessageType::ActiveSensing,
+        0xFF => MessageType::Reset,
+        // PortMidi delivers SysEx bodies as events whose first byte is a data byte or the closing F7
+        0xF0 | 0xF7 | 0x00..=0x7F => MessageType::SysEx,
+        0xF4 | 0xF5 | 0xF9 | 0xFD => MessageType::Undefined,
+    }
+}
+
 pub fn control_change(channel: u8, control: u8, value: u8) -> MidiMessage {
     MidiMessage {
         status: 0xB0 + channel,
@@ -144,6 +505,61 @@ pub fn control_change(channel: u8, control: u8, value: u8) -> MidiMessage {
     }
 }
 
+/// A 14-bit controller 0 to 31 as its MSB and then its LSB controller.
+pub fn control_change_14bit(channel: u8, control: u8, value: u16) -> [MidiMessage; 2] {
+    [
+        control_change(channel, control, (value >> 7) as u8 & 0x7F),
+        control_change(channel, control + 32, value as u8 & 0x7F),
+    ]
+}
+
+/// Selects the NRPN parameter and sets its 14-bit value with data entry.
+pub fn nrpn(channel: u8, parameter: u16, value: u16) -> [MidiMessage; 4] {
+    parameter_number(channel, 99, 98, parameter, value)
+}
+
+/// Selects the RPN parameter and sets its 14-bit value with data entry.
+pub fn rpn(channel: u8, parameter: u16, value: u16) -> [MidiMessage; 4] {
+    parameter_number(channel, 101, 100, parameter, value)
+}
+
+fn parameter_number(channel: u8, msb: u8, lsb: u8, parameter: u16, value: u16) -> [MidiMessage; 4] {
+    [
+        control_change(channel, msb, (parameter >> 7) as u8 & 0x7F),
+        control_change(channel, lsb, parameter as u8 & 0x7F),
+        control_change(channel, 6, (value >> 7) as u8 & 0x7F),
+        control_change(channel, 38, value as u8 & 0x7F),
+    ]
+}
+
+/// Pitch bend with the 14-bit value from 0 to 16383, centered at 8192.
+pub fn pitch_bend(channel: u8, value: u16) -> MidiMessage {
+    MidiMessage {
+        status: 0xE0 + channel,
+        data1: value as u8 & 0x7F,
+        data2: (value >> 7) as u8 & 0x7F,
+        data3: 0,
+    }
+}
+
+pub fn channel_pressure(channel: u8, pressure: u8) -> MidiMessage {
+    MidiMessage {
+        status: 0xD0 + channel,
+        data1: pressure,
+        data2: 0,
+        data3: 0,
+    }
+}
+
+pub fn poly_pressure(channel: u8, pitch: u8, pressure: u8) -> MidiMessage {
+    MidiMessage {
+        status: 0xA0 + channel,
+        data1: pitch,
+        data2: pressure,
+        data3: 0,
+    }
+}
+
 pub fn note_on(channel: u8, pitch: u8, velocity: u8) -> MidiMessage {
     MidiMessage {
         status: 0x90 + channel,
@@ -171,8 +587,41 @@ pub fn program_change(channel: u8, program: u8) -> MidiMessage {
     }
 }
 
+/// A single byte system real-time message, like clock, start or stop.
+pub fn realtime(status: u8) -> MidiMessage {
+    MidiMessage {
+        status,
+        data1: 0,
+        data2: 0,
+        data3: 0,
+    }
+}
+
 // MIDI Data ---------------------------------------------------------------------------------------
 
+/// Parses a SysEx message from hex bytes separated by spaces. `{channel}` is replaced by the channel
+/// as one hex digit, so `1{channel}` is a Yamaha style device ID, and `{0}`, `{1}` and so on by
+/// the values as whole bytes.
+pub fn parse_sysex(text: &str, channel: u8, values: &[u8]) -> Result<Vec<u8>, String> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for token in text.split_whitespace() {
+        let mut hex = token.replace("{channel}", &format!("{:X}", channel & 0x0F));
+        for (index, value) in values.iter().enumerate() {
+            hex = hex.replace(&format!("{{{}}}", index), &format!("{:02X}", value));
+        }
+        let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("sysex byte \"{}\" is not hex", token))?;
+        bytes.push(byte);
+    }
+
+    if bytes.first() != Some(&0xF0) || bytes.last() != Some(&0xF7) || bytes.len() < 2 {
+        return Err(format!("sysex \"{}\" doesn't start with F0 and end with F7", text));
+    }
+    if let Some(byte) = bytes[1..bytes.len() - 1].iter().find(|b| **b > 0x7F) {
+        return Err(format!("sysex \"{}\" has the status byte {:02X} in its data", text, byte));
+    }
+    Ok(bytes)
+}
+
 pub fn parse_midi_note(symbol: &String) -> u8 {
     match symbol.parse::<u8>() {
         Ok(n) => n,
@@ -239,7 +688,109 @@ pub fn parse_midi_note_symbol(symbol: &String) -> u8 {
 
 #[cfg(test)]
 mod tests {
-    use crate::midi::{parse_midi_note_symbol, parse_midi_note};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use portmidi::Direction;
+
+    use crate::midi::{
+        device_changes, inputs_to_open, message_type, nrpn, parse_midi_note_symbol, parse_midi_note, parse_sysex,
+        pitch_bend, rescan, update_input_ports, Device, DeviceSource,
+    };
+    use crate::models::{MessageType, MidiInput};
+
+    fn devices(names: &[(Direction, &str)]) -> Vec<(Direction, String)> {
+        names.iter().map(|(direction, name)| (*direction, name.to_string())).collect()
+    }
+
+    /// Like PortMidi, only lists the devices connected when it was last initialized.
+    struct FakeSource {
+        connected: Vec<Device>,
+        listed: Vec<Device>,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    struct FakePort {
+        device: String,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Drop for FakePort {
+        fn drop(&mut self) {
+            self.log.borrow_mut().push(format!("close {}", self.device));
+        }
+    }
+
+    impl DeviceSource for FakeSource {
+        type Port = FakePort;
+
+        fn devices(&mut self) -> Vec<Device> {
+            self.listed.clone()
+        }
+
+        fn open_input(&mut self, device_name: &str) -> Option<FakePort> {
+            self.log.borrow_mut().push(format!("open {}", device_name));
+            Some(FakePort { device: device_name.to_string(), log: Rc::clone(&self.log) })
+        }
+
+        fn reinitialize(&mut self) {
+            self.log.borrow_mut().push(String::from("reinitialize"));
+            self.listed = self.connected.clone();
+        }
+    }
+
+    #[test]
+    fn test_device_changes() {
+        let known = devices(&[(Direction::Input, "keys"), (Direction::Output, "synth"), (Direction::Input, "pads")]);
+        let current = devices(&[(Direction::Input, "keys"), (Direction::Output, "pads"), (Direction::Input, "pads")]);
+        let (connected, disconnected) = device_changes(&known, &current);
+
+        assert_eq!(connected, vec![&(Direction::Output, String::from("pads"))]);
+        assert_eq!(disconnected, vec![&(Direction::Output, String::from("synth"))]);
+        assert_eq!(device_changes(&known, &known), (vec![], vec![]));
+    }
+
+    #[test]
+    fn test_rescan() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let keys = devices(&[(Direction::Input, "keys")]);
+        let mut source = FakeSource { connected: keys.clone(), listed: keys.clone(), log: Rc::clone(&log) };
+        let inputs = vec![MidiInput::new(&String::from("keys")), MidiInput::new(&String::from("pads"))];
+        let mut ports = Vec::new();
+        update_input_ports(&mut source, &mut ports, &inputs, &keys);
+
+        // A device connected since the last scan is only listed after re-initializing, which needs the
+        // open ports closed first
+        source.connected.push((Direction::Input, String::from("pads")));
+        assert_eq!(source.devices(), keys);
+        let current = rescan(&mut source, &mut ports, &inputs, &keys);
+        assert_eq!(current, devices(&[(Direction::Input, "keys"), (Direction::Input, "pads")]));
+        assert_eq!(ports.iter().map(|(port, _)| port.device.as_str()).collect::<Vec<_>>(), vec!["keys", "pads"]);
+        assert_eq!(*log.borrow(), vec!["open keys", "close keys", "reinitialize", "open keys", "open pads"]);
+
+        // Disconnected devices aren't reopened
+        log.borrow_mut().clear();
+        source.connected.remove(0);
+        let current = rescan(&mut source, &mut ports, &inputs, &current);
+        assert_eq!(ports.iter().map(|(port, _)| port.device.as_str()).collect::<Vec<_>>(), vec!["pads"]);
+        assert_eq!(*log.borrow(), vec!["close keys", "close pads", "reinitialize", "open pads"]);
+        assert_eq!(current, devices(&[(Direction::Input, "pads")]));
+    }
+
+    #[test]
+    fn test_inputs_to_open() {
+        let inputs = vec![
+            MidiInput::new(&String::from("keys")),
+            MidiInput::new(&String::from("pads")),
+            MidiInput::new(&String::from("synth")),
+        ];
+        let devices = devices(&[(Direction::Input, "keys"), (Direction::Input, "pads"), (Direction::Output, "synth")]);
+
+        // Open ports are kept, and outputs or missing devices aren't opened as inputs
+        let open = inputs_to_open(&inputs, &devices, &[String::from("keys")]);
+        assert_eq!(open.iter().map(|i| i.device.as_str()).collect::<Vec<_>>(), vec!["pads"]);
+        assert!(inputs_to_open(&inputs, &devices, &[String::from("keys"), String::from("pads")]).is_empty());
+    }
 
     #[test]
     fn test_parse_midi_note_symbol() {
@@ -271,5 +822,38 @@ mod tests {
         assert_eq!(parse_midi_note(&"111".to_string()), 111);
 
     }
-}
 
+    #[test]
+    fn test_message_type() {
+        assert_eq!(message_type(0x80), MessageType::NoteOff);
+        assert_eq!(message_type(0x9F), MessageType::NoteOn);
+        assert_eq!(message_type(0xB6), MessageType::ControlChange);
+        assert_eq!(message_type(0xE0), MessageType::PitchBend);
+        assert_eq!(message_type(0xF0), MessageType::SysEx);
+        assert_eq!(message_type(0x12), MessageType::SysEx);
+        assert_eq!(message_type(0xF8), MessageType::Clock);
+        assert_eq!(message_type(0xFE), MessageType::ActiveSensing);
+        assert_eq!(message_type(0xF4), MessageType::Undefined);
+        assert_eq!(message_type(0xF7), MessageType::SysEx);
+        assert_eq!(message_type(0xFD), MessageType::Undefined);
+    }
+
+    #[test]
+    fn test_14bit_messages() {
+        let bend = pitch_bend(1, 8192 + 100);
+        assert_eq!((bend.status, bend.data1, bend.data2), (0xE1, 100, 64));
+
+        let values = nrpn(0, 1234, 5000).iter().map(|m| (m.status, m.data1, m.data2)).collect::<Vec<_>>();
+        assert_eq!(values, vec![(0xB0, 99, 9), (0xB0, 98, 82), (0xB0, 6, 39), (0xB0, 38, 8)]);
+    }
+
+    #[test]
+    fn test_parse_sysex() {
+        let message = parse_sysex("F0 43 1{channel} 4C {0} {1} F7", 11, &[2, 100]);
+        assert_eq!(message, Ok(vec![0xF0, 0x43, 0x1B, 0x4C, 0x02, 0x64, 0xF7]));
+
+        assert!(parse_sysex("F0 43 10 F7 00", 0, &[]).is_err());
+        assert!(parse_sysex("F0 43 {0} F7", 0, &[]).is_err());
+        assert!(parse_sysex("F0 {0} F7", 0, &[200]).is_err());
+    }
+}
@@ -18,15 +18,28 @@
  */
 use serde::{Deserialize, Serialize};
 
-use crate::config::{DEFAULT_MIDI_CHANNEL, DEFAULT_PARTS_PER_QUARTER};
+use crate::midi::parse_sysex;
+
+use crate::config::{DEFAULT_MIDI_CHANNEL, DEFAULT_PARTS_PER_QUARTER, MAX_SEQUENCE_STEPS, MAX_TEMPO_BPM, MIN_TEMPO_BPM};
 
 // Controller --------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Controller {
     pub device: String,
     pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub ppq: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reload: Option<Quantize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clock: Option<ClockSource>,
+    /// The tempo of the internal clock until a scene sets one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tempo: Option<f64>,
+    /// Devices that are sent MIDI clock, start and stop as the performance plays.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync: Option<Vec<String>>,
 }
 
 impl Clone for Controller {
@@ -35,28 +48,241 @@ impl Clone for Controller {
             device: self.device.to_owned(),
             channel: self.channel.to_owned(),
             ppq: self.ppq.to_owned(),
+            reload: self.reload.to_owned(),
+            clock: self.clock.to_owned(),
+            tempo: self.tempo.to_owned(),
+            sync: self.sync.to_owned(),
         }
     }
 }
 
 impl Controller {
-    #[allow(dead_code)]
     pub fn new() -> Controller {
         Controller {
             device: String::new(),
             channel: DEFAULT_MIDI_CHANNEL,
             ppq: Some(DEFAULT_PARTS_PER_QUARTER),
+            reload: None,
+            clock: None,
+            tempo: None,
+            sync: None,
+        }
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Controller {
+        Controller::new()
+    }
+}
+
+/// Where the clock comes from. The source is chosen at startup, reloading doesn't switch it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClockSource {
+    /// MIDI clock from the controller device.
+    External,
+    /// A clock generated at the tempo of the controller and the scenes.
+    Internal,
+}
+
+// MidiInput ---------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MessageType {
+    NoteOff,
+    NoteOn,
+    PolyAftertouch,
+    ControlChange,
+    ProgramChange,
+    ChannelAftertouch,
+    PitchBend,
+    SysEx,
+    TimeCode,
+    SongPosition,
+    SongSelect,
+    TuneRequest,
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    ActiveSensing,
+    Reset,
+    /// The undefined status bytes F4, F5, F9 and FD.
+    Undefined,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MidiInput {
+    pub device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ignore: Option<Vec<MessageType>>,
+}
+
+impl Clone for MidiInput {
+    fn clone(&self) -> MidiInput {
+        MidiInput {
+            device: self.device.to_owned(),
+            ignore: self.ignore.to_owned(),
+        }
+    }
+}
+
+impl MidiInput {
+    pub fn new(device: &String) -> MidiInput {
+        MidiInput {
+            device: device.to_owned(),
+            ignore: None,
+        }
+    }
+
+    pub fn accepts(&self, message_type: MessageType) -> bool {
+        match &self.ignore {
+            Some(ignore) => !ignore.contains(&message_type),
+            None => true,
+        }
+    }
+}
+
+// Route -------------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VelocityCurve {
+    Linear,
+    Soft,
+    Hard,
+    Fixed(u8),
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct RouteTarget {
+    pub device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+}
+
+impl Clone for RouteTarget {
+    fn clone(&self) -> RouteTarget {
+        RouteTarget {
+            device: self.device.to_owned(),
+            channel: self.channel.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    pub to: Vec<RouteTarget>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transpose: Option<i8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<VelocityCurve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Vec<String>>,
+}
+
+impl Clone for Route {
+    fn clone(&self) -> Route {
+        Route {
+            device: self.device.to_owned(),
+            channel: self.channel.to_owned(),
+            to: self.to.to_vec(),
+            transpose: self.transpose.to_owned(),
+            velocity: self.velocity.to_owned(),
+            range: self.range.to_owned(),
+        }
+    }
+}
+
+// Control -----------------------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControlAction {
+    Jump(String),
+    Next,
+    Previous,
+    Hold,
+    Loop,
+    Restart,
+    Mute(String),
+    Solo(String),
+    /// Plays the fill sequences and steps until the end of the bar.
+    Fill,
+}
+
+/// Ordered from the finest to the coarsest boundary, so an action is due at any boundary that is at
+/// least as coarse as its own quantization.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Quantize {
+    Immediate,
+    Beat,
+    Bar,
+    Scene,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControlMapping {
+    pub device: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program: Option<u8>,
+    pub action: ControlAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantize: Option<Quantize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub led: Option<Vec<u8>>,
+}
+
+impl Clone for ControlMapping {
+    fn clone(&self) -> ControlMapping {
+        ControlMapping {
+            device: self.device.to_owned(),
+            channel: self.channel.to_owned(),
+            note: self.note.to_owned(),
+            control: self.control.to_owned(),
+            program: self.program.to_owned(),
+            action: self.action.clone(),
+            quantize: self.quantize.to_owned(),
+            led: self.led.to_owned(),
         }
     }
 }
 
 // Track -------------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Track {
     pub instrument: String,
-    pub follow: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follow: Option<TrackFollow>,
     pub play: Vec<String>,
+    /// The sequence played instead while a fill is active.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fill: Option<String>,
+}
+
+impl Track {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.play.is_empty() {
+            return Err(format!("track \"{}\" plays no sequences", self.instrument));
+        }
+        if self.follow.as_ref().is_some_and(|f| f.every() == 0) {
+            return Err(format!("track \"{}\" follows every 0 notes", self.instrument));
+        }
+        Ok(())
+    }
 }
 
 impl Clone for Track {
@@ -65,16 +291,132 @@ impl Clone for Track {
             instrument: self.instrument.to_owned(),
             follow: self.follow.to_owned(),
             play: self.play.to_vec(),
+            fill: self.fill.to_owned(),
+        }
+    }
+}
+
+/// The instrument whose notes advance a track, either just its name or with options like
+/// `{ instrument: drum1, every: 2, pitch: C3, reset: true }`. The instrument may be played by
+/// another follower.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TrackFollow {
+    Instrument(String),
+    Options(FollowOptions),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FollowOptions {
+    pub instrument: String,
+    /// Advances on every nth note instead of every note.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub every: Option<usize>,
+    /// Only counts the notes of this pitch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pitch: Option<String>,
+    /// Goes back to the first sequence whenever the followed track starts its sequences over.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset: Option<bool>,
+}
+
+impl TrackFollow {
+    pub fn instrument(&self) -> &String {
+        match self {
+            TrackFollow::Instrument(instrument) => instrument,
+            TrackFollow::Options(options) => &options.instrument,
+        }
+    }
+
+    pub fn every(&self) -> usize {
+        match self {
+            TrackFollow::Instrument(_) => 1,
+            TrackFollow::Options(options) => options.every.unwrap_or(1),
+        }
+    }
+
+    pub fn pitch(&self) -> Option<&String> {
+        match self {
+            TrackFollow::Instrument(_) => None,
+            TrackFollow::Options(options) => options.pitch.as_ref(),
+        }
+    }
+
+    pub fn reset(&self) -> bool {
+        match self {
+            TrackFollow::Instrument(_) => false,
+            TrackFollow::Options(options) => options.reset.unwrap_or(false),
         }
     }
 }
 
 // Scene -------------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Scene {
     pub name: String,
     pub tracks: Vec<Track>,
+    /// The number of bars, which otherwise is the number of sequences the first track plays.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Sets the tempo when the scene starts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tempo: Option<f64>,
+    /// Changes the tempo gradually once the scene started.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ramp: Option<TempoRamp>,
+    /// What happens when the scene ends, one of the actions is picked at random by weight.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub follow: Option<Vec<FollowChoice>>,
+    /// SysEx messages sent when the scene starts, like patch dumps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sysex: Option<Vec<SysEx>>,
+}
+
+impl Scene {
+    pub fn new(name: &str) -> Scene {
+        Scene {
+            name: name.to_string(),
+            tracks: Vec::new(),
+            length: None,
+            tempo: None,
+            ramp: None,
+            follow: None,
+            sysex: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.tracks.is_empty() {
+            return Err(format!("scene \"{}\" has no tracks", self.name));
+        }
+        if let Some(follow) = &self.follow {
+            match follow.iter().try_fold(0u32, |total, f| total.checked_add(f.weight())) {
+                Some(0) => return Err(format!("scene \"{}\" has no follow action with a weight", self.name)),
+                None => return Err(format!("scene \"{}\" has follow weights above {} in total", self.name, u32::MAX)),
+                Some(_) => {}
+            }
+        }
+        for tempo in self.tempo.iter().chain(self.ramp.as_ref().map(|r| &r.to)) {
+            validate_tempo(*tempo).map_err(|e| format!("scene \"{}\": {}", self.name, e))?;
+        }
+        if self.length == Some(0) {
+            return Err(format!("scene \"{}\" has a length of 0 bars", self.name));
+        }
+        if self.ramp.as_ref().is_some_and(|r| r.bars == 0) {
+            return Err(format!("scene \"{}\" ramps the tempo over 0 bars", self.name));
+        }
+        for sysex in self.sysex.iter().flatten() {
+            if sysex.device.is_none() {
+                return Err(format!("scene \"{}\" sends sysex without a device", self.name));
+            }
+            sysex.validate().map_err(|e| format!("scene \"{}\": {}", self.name, e))?;
+        }
+        for track in &self.tracks {
+            track.validate().map_err(|e| format!("scene \"{}\": {}", self.name, e))?;
+        }
+        Ok(())
+    }
 }
 
 impl Clone for Scene {
@@ -82,53 +424,257 @@ impl Clone for Scene {
         Scene {
             name: self.name.to_owned(),
             tracks: self.tracks.to_vec(),
+            length: self.length.to_owned(),
+            tempo: self.tempo.to_owned(),
+            ramp: self.ramp.to_owned(),
+            follow: self.follow.to_owned(),
+            sysex: self.sysex.to_owned(),
+        }
+    }
+}
+
+/// Moves the tempo linearly from where it is at the start of the scene to `to` over `bars` bars.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TempoRamp {
+    pub to: f64,
+    pub bars: usize,
+}
+
+/// Where the playlist goes when a scene ends, like the follow actions of a clip in Ableton Live.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FollowAction {
+    /// The next entry of the playlist, which is what scenes without follow actions do.
+    Next,
+    Previous,
+    First,
+    /// Any entry of the playlist, including the one that just ended.
+    Random,
+    /// Any entry that plays a different scene.
+    RandomOther,
+    /// The first entry that plays the scene, or the start of the section.
+    Jump(String),
+    /// Plays the scene again.
+    Loop,
+}
+
+/// A follow action, either on its own or as `{ action, weight }`. Actions without a weight have a
+/// weight of 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FollowChoice {
+    Action(FollowAction),
+    Weighted { action: FollowAction, weight: u32 },
+}
+
+impl FollowChoice {
+    pub fn action(&self) -> &FollowAction {
+        match self {
+            FollowChoice::Action(action) => action,
+            FollowChoice::Weighted { action, .. } => action,
+        }
+    }
+
+    pub fn weight(&self) -> u32 {
+        match self {
+            FollowChoice::Action(_) => 1,
+            FollowChoice::Weighted { weight, .. } => *weight,
         }
     }
 }
 
 // SequenceStep ------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct SequenceStep {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub pitch: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub velocity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<Vec<u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub program: Option<u8>,
+    /// Plays the step only during fills if true, or only outside of them if false.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fill: Option<bool>,
+    /// Pitch bend from -8192 to 8191, sent before the notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bend: Option<i16>,
+    /// Channel aftertouch, sent after the notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aftertouch: Option<u8>,
+    /// Aftertouch for each note of the step, sent after the notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poly_aftertouch: Option<u8>,
+    /// NRPN parameters to set, sent before the notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nrpn: Option<Vec<ParameterValue>>,
+    /// RPN parameters to set, like the pitch bend range, sent before the notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpn: Option<Vec<ParameterValue>>,
+    /// 14-bit controllers 0 to 31, sent with their LSB controller 32 to 63 before the notes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc14: Option<Vec<ParameterValue>>,
+    /// SysEx messages sent before the notes, to the device of the instrument unless they name one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sysex: Option<Vec<SysEx>>,
 }
 
 impl Clone for SequenceStep {
     fn clone(&self) -> SequenceStep {
         SequenceStep {
-            pitch: match &self.pitch {
-                Some(p) => Some(p.to_vec()),
-                None => None,
-            },
+            pitch: self.pitch.to_owned(),
             velocity: self.velocity.to_owned(),
-            data: match &self.data {
-                Some(d) => Some(d.to_vec()),
-                None => None,
-            },
-            program: self.program.to_owned()
+            data: self.data.to_owned(),
+            program: self.program.to_owned(),
+            fill: self.fill.to_owned(),
+            bend: self.bend.to_owned(),
+            aftertouch: self.aftertouch.to_owned(),
+            poly_aftertouch: self.poly_aftertouch.to_owned(),
+            nrpn: self.nrpn.to_owned(),
+            rpn: self.rpn.to_owned(),
+            cc14: self.cc14.to_owned(),
+            sysex: self.sysex.to_owned(),
+        }
+    }
+}
+
+impl SequenceStep {
+    pub fn new() -> SequenceStep {
+        SequenceStep {
+            pitch: None,
+            velocity: None,
+            data: None,
+            program: None,
+            fill: None,
+            bend: None,
+            aftertouch: None,
+            poly_aftertouch: None,
+            nrpn: None,
+            rpn: None,
+            cc14: None,
+            sysex: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(bend) = self.bend.filter(|b| !(-8192..=8191).contains(b)) {
+            return Err(format!("bend {} is not between -8192 and 8191", bend));
+        }
+        if self.aftertouch.iter().chain(self.poly_aftertouch.iter()).any(|a| *a > 127) {
+            return Err(String::from("aftertouch is not between 0 and 127"));
         }
+        let parameters = self.nrpn.iter().chain(self.rpn.iter()).flatten();
+        for parameter in parameters.chain(self.cc14.iter().flatten()) {
+            if parameter.number > 16383 || parameter.value > 16383 {
+                return Err(format!("parameter {} is not 14 bits", parameter.number));
+            }
+        }
+        if let Some(parameter) = self.cc14.iter().flatten().find(|p| p.number > 31) {
+            return Err(format!("controller {} has no LSB controller", parameter.number));
+        }
+        for sysex in self.sysex.iter().flatten() {
+            sysex.validate()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for SequenceStep {
+    fn default() -> SequenceStep {
+        SequenceStep::new()
+    }
+}
+
+// SysEx -------------------------------------------------------------------------------------------
+
+/// A SysEx message as hex bytes like `F0 43 1{channel} 4C 02 01 00 {0} F7`. `{channel}` is replaced by
+/// the channel from 0 to 15, as most device IDs are, and `{0}`, `{1}` and so on by the values.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SysEx {
+    pub data: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<u8>>,
+    /// The device to send to, which scenes need and steps default to their instrument's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    /// The channel for `{channel}`, which steps default to their instrument's and scenes to 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+}
+
+impl SysEx {
+    pub fn new(data: &str) -> SysEx {
+        SysEx {
+            data: data.to_string(),
+            values: None,
+            device: None,
+            channel: None,
+        }
+    }
+
+    /// The message bytes, with the placeholders filled in for the channel from 0 to 15.
+    pub fn message(&self, default_channel: u8) -> Result<Vec<u8>, String> {
+        let channel = self.channel.map_or(default_channel, |c| c.wrapping_sub(1));
+        parse_sysex(&self.data, channel, self.values.as_ref().map_or(&[], |v| v.as_slice()))
     }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(channel) = self.channel {
+            validate_channel(channel).map_err(|e| format!("sysex: {}", e))?;
+        }
+        self.message(0).map(|_| ())
+    }
+}
+
+// ParameterValue ----------------------------------------------------------------------------------
+
+/// A 14-bit value for an NRPN or RPN parameter or a 14-bit controller.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterValue {
+    pub number: u16,
+    pub value: u16,
 }
 
 // Sequence ----------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Sequence {
     pub name: String,
     pub steps: Vec<Option<SequenceStep>>,
 }
 
 impl Sequence {
-    #[allow(dead_code)]
     pub fn new() -> Sequence {
         Sequence {
             name: String::new(),
             steps: Vec::new(),
         }
     }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.steps.is_empty() || self.steps.len() > MAX_SEQUENCE_STEPS {
+            return Err(format!(
+                "sequence \"{}\" has {} steps, expected 1 to {}",
+                self.name,
+                self.steps.len(),
+                MAX_SEQUENCE_STEPS
+            ));
+        }
+        for (index, step) in self.steps.iter().enumerate() {
+            if let Some(step) = step {
+                step.validate().map_err(|e| format!("sequence \"{}\" step {}: {}", self.name, index + 1, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Sequence {
+        Sequence::new()
+    }
 }
 
 impl Clone for Sequence {
@@ -142,7 +688,7 @@ impl Clone for Sequence {
 
 // ModDevice ---------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct ModDevice {
     pub device: String,
     pub channel: u8,
@@ -161,17 +707,17 @@ impl Clone for ModDevice {
 
 // Instrument --------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Instrument {
     pub name: String,
     pub device: String,
     pub channel: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<Vec<ModDevice>>,
     pub sequences: Vec<Sequence>,
 }
 
 impl Instrument {
-    #[allow(dead_code)]
     pub fn new() -> Instrument {
         Instrument {
             name: String::new(),
@@ -182,7 +728,21 @@ impl Instrument {
         }
     }
 
-    #[allow(dead_code)]
+    pub fn validate(&self) -> Result<(), String> {
+        let context = |e: String| format!("instrument \"{}\": {}", self.name, e);
+        if self.name.is_empty() {
+            return Err(String::from("instrument without a name"));
+        }
+        validate_channel(self.channel).map_err(context)?;
+        for mod_device in self.data.iter().flatten() {
+            validate_channel(mod_device.channel).map_err(context)?;
+        }
+        for sequence in &self.sequences {
+            sequence.validate().map_err(context)?;
+        }
+        Ok(())
+    }
+
     pub fn find_sequence(&self, name: &String) -> Option<&Sequence> {
         let mut result: Option<&Sequence> = None;
         for sequence in &self.sequences {
@@ -194,43 +754,263 @@ impl Instrument {
     }
 }
 
+impl Default for Instrument {
+    fn default() -> Instrument {
+        Instrument::new()
+    }
+}
+
 impl Clone for Instrument {
     fn clone(&self) -> Instrument {
         Instrument {
             name: self.name.to_string(),
             device: self.device.to_string(),
             channel: self.channel.to_owned(),
-            data: match &self.data {
-                Some(d) => Some(d.to_vec()),
-                None => None,
-            },
+            data: self.data.as_ref().map(|d| d.to_vec()),
             sequences: self.sequences.to_vec(),
         }
     }
 }
 
+// Playlist ----------------------------------------------------------------------------------------
+
+/// An entry of the playlist or of a section, either just the name of a scene or a scene or section
+/// with options, like `{ scene: intro, repeat: 4, bars: 8 }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PlaylistEntry {
+    Scene(String),
+    Entry(ArrangementEntry),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArrangementEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scene: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+    /// Plays the scene or section this many times in a row.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<usize>,
+    /// Overrides the length of the scene.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bars: Option<usize>,
+}
+
+impl PlaylistEntry {
+    pub fn scene(name: &str) -> PlaylistEntry {
+        PlaylistEntry::Scene(name.to_string())
+    }
+
+    pub fn section(name: &str) -> PlaylistEntry {
+        PlaylistEntry::Entry(ArrangementEntry {
+            scene: None,
+            section: Some(name.to_string()),
+            repeat: None,
+            bars: None,
+        })
+    }
+
+    pub fn repeat(self, count: usize) -> PlaylistEntry {
+        let mut entry = self.into_entry();
+        entry.repeat = Some(count);
+        PlaylistEntry::Entry(entry)
+    }
+
+    pub fn bars(self, bars: usize) -> PlaylistEntry {
+        let mut entry = self.into_entry();
+        entry.bars = Some(bars);
+        PlaylistEntry::Entry(entry)
+    }
+
+    fn into_entry(self) -> ArrangementEntry {
+        match self {
+            PlaylistEntry::Scene(name) => ArrangementEntry {
+                scene: Some(name),
+                section: None,
+                repeat: None,
+                bars: None,
+            },
+            PlaylistEntry::Entry(entry) => entry,
+        }
+    }
+
+    pub fn scene_name(&self) -> Option<&String> {
+        match self {
+            PlaylistEntry::Scene(name) => Some(name),
+            PlaylistEntry::Entry(entry) => entry.scene.as_ref(),
+        }
+    }
+
+    pub fn section_name(&self) -> Option<&String> {
+        match self {
+            PlaylistEntry::Scene(_) => None,
+            PlaylistEntry::Entry(entry) => entry.section.as_ref(),
+        }
+    }
+
+    pub fn repeat_count(&self) -> usize {
+        match self {
+            PlaylistEntry::Scene(_) => 1,
+            PlaylistEntry::Entry(entry) => entry.repeat.unwrap_or(1),
+        }
+    }
+
+    pub fn bar_count(&self) -> Option<usize> {
+        match self {
+            PlaylistEntry::Scene(_) => None,
+            PlaylistEntry::Entry(entry) => entry.bars,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let entry = match self {
+            PlaylistEntry::Scene(_) => return Ok(()),
+            PlaylistEntry::Entry(entry) => entry,
+        };
+        if entry.scene.is_some() == entry.section.is_some() {
+            return Err(String::from("playlist entries need either a scene or a section"));
+        }
+        if entry.repeat == Some(0) || entry.bars == Some(0) {
+            return Err(String::from("playlist entries can't repeat 0 times or last 0 bars"));
+        }
+        if entry.section.is_some() && entry.bars.is_some() {
+            return Err(String::from("sections in the playlist can't set bars"));
+        }
+        Ok(())
+    }
+}
+
+/// A named part of the playlist, like a verse and chorus that are played several times.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Section {
+    pub name: String,
+    pub playlist: Vec<PlaylistEntry>,
+}
+
+impl Clone for Section {
+    fn clone(&self) -> Section {
+        Section {
+            name: self.name.to_owned(),
+            playlist: self.playlist.to_vec(),
+        }
+    }
+}
+
+/// What happens after the last entry of the playlist.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlaylistEnd {
+    /// Starts over with the first entry.
+    Loop,
+    /// Stops playing until the performance is restarted or jumps to a scene.
+    Stop,
+    /// Keeps repeating the last scene.
+    Hold,
+}
+
 // Performance -------------------------------------------------------------------------------------
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Performance {
     pub controller: Controller,
-    pub playlist: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inputs: Option<Vec<MidiInput>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub routes: Option<Vec<Route>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control: Option<Vec<ControlMapping>>,
+    pub playlist: Vec<PlaylistEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sections: Option<Vec<Section>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<PlaylistEnd>,
     pub scenes: Vec<Scene>,
     pub instruments: Vec<Instrument>,
 }
 
+impl Clone for Performance {
+    fn clone(&self) -> Performance {
+        Performance {
+            controller: self.controller.clone(),
+            inputs: self.inputs.to_owned(),
+            routes: self.routes.to_owned(),
+            control: self.control.to_owned(),
+            playlist: self.playlist.to_vec(),
+            sections: self.sections.to_owned(),
+            end: self.end.to_owned(),
+            scenes: self.scenes.to_vec(),
+            instruments: self.instruments.to_vec(),
+        }
+    }
+}
+
 impl Performance {
-    #[allow(dead_code)]
     pub fn new() -> Performance {
         Performance {
             controller: Controller::new(),
+            inputs: None,
+            routes: None,
+            control: None,
             playlist: Vec::new(),
+            sections: None,
+            end: None,
             scenes: Vec::new(),
             instruments: Vec::new(),
         }
     }
 
-    #[allow(dead_code)]
+    /// Checks everything the types alone don't guarantee but the player relies on. References to
+    /// unknown scenes, instruments or sequences are allowed and just don't play.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.playlist.is_empty() {
+            return Err(String::from("playlist is empty"));
+        }
+        for entry in &self.playlist {
+            entry.validate()?;
+            if let Some(name) = entry.section_name() {
+                if self.find_section(name).is_none() {
+                    return Err(format!("playlist plays unknown section \"{}\"", name));
+                }
+            }
+        }
+        for section in self.sections.iter().flatten() {
+            if section.playlist.is_empty() {
+                return Err(format!("section \"{}\" is empty", section.name));
+            }
+            for entry in &section.playlist {
+                entry.validate().map_err(|e| format!("section \"{}\": {}", section.name, e))?;
+                if entry.section_name().is_some() {
+                    return Err(format!("section \"{}\" plays another section", section.name));
+                }
+            }
+        }
+        if let Some(tempo) = self.controller.tempo {
+            validate_tempo(tempo).map_err(|e| format!("controller: {}", e))?;
+        }
+        for route in self.routes.iter().flatten() {
+            let targets = route.to.iter().map(|t| t.channel);
+            for channel in route.channel.into_iter().chain(targets.flatten()) {
+                validate_channel(channel).map_err(|e| format!("route from \"{}\": {}", route.device, e))?;
+            }
+        }
+        for mapping in self.control.iter().flatten() {
+            if let Some(channel) = mapping.channel {
+                validate_channel(channel).map_err(|e| format!("control on \"{}\": {}", mapping.device, e))?;
+            }
+        }
+        for scene in &self.scenes {
+            scene.validate()?;
+        }
+        for (index, instrument) in self.instruments.iter().enumerate() {
+            instrument.validate()?;
+            if self.instruments[..index].iter().any(|i| i.name == instrument.name) {
+                return Err(format!("instrument \"{}\" is defined more than once", instrument.name));
+            }
+        }
+        Ok(())
+    }
+
     pub fn find_scene(&self, name: &String) -> Option<&Scene> {
         let mut result: Option<&Scene> = None;
         for scene in &self.scenes {
@@ -241,7 +1021,10 @@ impl Performance {
         result
     }
 
-    #[allow(dead_code)]
+    pub fn find_section(&self, name: &String) -> Option<&Section> {
+        self.sections.iter().flatten().find(|s| &s.name == name)
+    }
+
     pub fn find_instrument(&self, name: &String) -> Option<&Instrument> {
         let mut result: Option<&Instrument> = None;
         for instrument in &self.instruments {
@@ -251,4 +1034,49 @@ impl Performance {
         }
         result
     }
+    /// All MIDI inputs the performance listens to, one per device. Devices that are referenced
+    /// without an entry in `inputs` accept every message type.
+    pub fn midi_inputs(&self) -> Vec<MidiInput> {
+        let mut result: Vec<MidiInput> = Vec::new();
+        if let Some(inputs) = &self.inputs {
+            for input in inputs {
+                if !result.iter().any(|i| i.device == input.device) {
+                    result.push(input.clone());
+                }
+            }
+        }
+        let mut referenced: Vec<&String> = vec![&self.controller.device];
+        if let Some(routes) = &self.routes {
+            referenced.extend(routes.iter().map(|r| &r.device));
+        }
+        if let Some(control) = &self.control {
+            referenced.extend(control.iter().map(|c| &c.device));
+        }
+        for device in referenced {
+            if !result.iter().any(|i| &i.device == device) {
+                result.push(MidiInput::new(device));
+            }
+        }
+        result
+    }
+}
+
+impl Default for Performance {
+    fn default() -> Performance {
+        Performance::new()
+    }
+}
+
+fn validate_channel(channel: u8) -> Result<(), String> {
+    if !(1..=16).contains(&channel) {
+        return Err(format!("channel {} is not between 1 and 16", channel));
+    }
+    Ok(())
+}
+
+fn validate_tempo(tempo: f64) -> Result<(), String> {
+    if !(MIN_TEMPO_BPM..=MAX_TEMPO_BPM).contains(&tempo) {
+        return Err(format!("tempo {} is not between {} and {} BPM", tempo, MIN_TEMPO_BPM, MAX_TEMPO_BPM));
+    }
+    Ok(())
 }
@@ -16,237 +16,797 @@
  * You should have received a copy of the GNU General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
-use std::time::{Duration};
+use std::time::Instant;
+
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
 
 use crate::context::Context;
-use crate::midi::DeviceManager;
-use crate::models::{Controller, Performance};
+use crate::control::feedback_message;
+use crate::diff::diff_performance;
+use crate::midi::{realtime, DeviceManager, MidiOutput};
+use crate::models::{ControlAction, FollowAction, MidiInput, Performance, PlaylistEnd, Quantize};
 use crate::performance_file::{load_performance_file, start_file_watcher};
+use crate::plan::{InstrumentPlan, PerformancePlan};
+use crate::random::Random;
 use crate::sequence_player::SequencePlayer;
-use crate::config::{TICKS_PER_MEASURE, CLOCK_MULTIPLIER};
+use crate::stats::{StatsCollector, TimingStats};
+use crate::config::{TICKS_PER_BEAT, TICKS_PER_MEASURE, CLOCK_MULTIPLIER, DEFAULT_TEMPO_BPM};
 use crate::log;
 
-pub fn start_performance(
-    context: &Context,
-    clock_reset_recv: Receiver<bool>,
-    mult_clock_recv: Receiver<u64>,
-    ctrl_updated: Sender<Controller>,
-) -> Controller {
-    let mut perf =
-        load_performance_file(OsStr::new(&context.performance.to_owned()))
-            .expect("Failed to load file");
-
-    let ctrl: Controller = perf.controller.clone();
+/// The channels between the performance thread and the other threads of the controller.
+pub struct PerformanceChannels {
+    pub clock_reset_recv: Receiver<bool>,
+    pub mult_clock_recv: Receiver<(u64, Instant)>,
+    pub perf_updated: Sender<Performance>,
+    pub midi_inputs: Sender<Vec<MidiInput>>,
+    pub action_recv: Receiver<(ControlAction, Quantize)>,
+    pub tempo_send: Sender<f64>,
+    pub stats_send: Option<Sender<TimingStats>>,
+}
 
+pub fn start_performance(context: &Context, channels: PerformanceChannels) -> Performance {
+    let PerformanceChannels {
+        clock_reset_recv,
+        mult_clock_recv,
+        perf_updated,
+        midi_inputs,
+        action_recv,
+        tempo_send,
+        stats_send,
+    } = channels;
     let (perf_update_send, perf_update_recv): (Sender<Performance>, Receiver<Performance>) =
-        channel();
+        unbounded();
 
     start_file_watcher(&context.performance.to_owned(), perf_update_send);
 
-    thread::spawn(move || {
-        let mut perf_ctrl = PerformanceController::new(perf);
+    let perf = match load_performance_file(OsStr::new(&context.performance.to_owned())) {
+        Ok(perf) => perf,
+        Err(e) if context.strict => panic!("Failed to load file: {}", e),
+        Err(e) => {
+            log::error(format!("ERROR loading {:?}, waiting for a fix\n{}", context.performance, e), 0);
+            perf_update_recv.recv().expect("file watcher stopped")
+        }
+    };
 
-        let wait_dur = Duration::from_micros(1);
+    let perf_def: Performance = perf.clone();
+    midi_inputs.send(perf.midi_inputs()).unwrap();
+
+    thread::spawn(move || {
+        let mut perf_ctrl = PerformanceController::new(perf, DeviceManager::new());
+        perf_ctrl.set_random(Random::from_time());
+        let mut stats = StatsCollector::new(stats_send);
+        let mut tempo: f64 = 0.0;
 
         loop {
-            let perf_update = perf_update_recv.try_recv();
-            if perf_update.is_ok() {
-                perf = perf_update.unwrap();
-                ctrl_updated.send(perf.controller.clone()).unwrap();
-                perf_ctrl.update_def(perf);
+            // Only the internal clock follows the tempo, the send fails without it
+            if perf_ctrl.tempo() != tempo {
+                tempo = perf_ctrl.tempo();
+                tempo_send.send(tempo).ok();
             }
-            let reset_msg = clock_reset_recv.try_recv();
-            if reset_msg.is_ok() {
-                perf_ctrl.reset();
+            if stats.is_due() {
+                stats.stats.merge(&perf_ctrl.take_stats());
+                stats.stats.merge(&perf_ctrl.output().take_stats());
+                stats.send_due();
             }
-            let clock_msg = mult_clock_recv.try_recv();
-            if clock_msg.is_ok() {
-                // let now = SystemTime::now();
-                perf_ctrl.clock(clock_msg.unwrap());
-                // println!("elapsed={}us", now.elapsed().unwrap().as_micros());
+
+            select! {
+                recv(perf_update_recv) -> msg => {
+                    perf_ctrl.stage_def(msg.expect("file watcher stopped"));
+                }
+                recv(action_recv) -> msg => {
+                    let (action, quantize) = msg.expect("action channel closed");
+                    perf_ctrl.queue_action(action, quantize);
+                }
+                recv(clock_reset_recv) -> msg => {
+                    msg.expect("clock reset channel closed");
+                    perf_ctrl.reset();
+                }
+                recv(mult_clock_recv) -> msg => {
+                    let (tick_count, scheduled) = msg.expect("clock multiplier stopped");
+                    perf_ctrl.output().schedule(Some(scheduled));
+                    perf_ctrl.clock(tick_count);
+                    perf_ctrl.output().schedule(None);
+                }
+            }
+
+            // Routes, control mappings and inputs switch at the same boundary as the performance
+            if let Some(def) = perf_ctrl.take_applied_def() {
+                midi_inputs.send(def.midi_inputs()).unwrap();
+                perf_updated.send(def).unwrap();
             }
-            thread::sleep(wait_dur);
         }
     });
 
-    ctrl
+    perf_def
 }
 
 // PerformanceController ---------------------------------------------------------------------------
 
-struct PerformanceController {
+pub struct PerformanceController<O: MidiOutput> {
     scene_index: usize,
     clock_count: usize,
     bar_count: usize,
-    players: HashMap<String, SequencePlayer>,
+    players: Vec<Option<SequencePlayer>>,
     perf: Performance,
-    device_manager: DeviceManager
+    plan: PerformancePlan,
+    track_state: Vec<TrackState>,
+    device_manager: O,
+    pending_actions: Vec<(ControlAction, Quantize)>,
+    hold_bar: bool,
+    loop_scene: bool,
+    /// Set by a fill action until the end of the bar.
+    fill: bool,
+    /// Set when the playlist ended with `end: stop`, until the next scene change or reset.
+    finished: bool,
+    muted: HashSet<String>,
+    soloed: HashSet<String>,
+    staged_def: Option<(Performance, Quantize)>,
+    /// Set when the staged definition was applied, until it is taken.
+    applied_def: bool,
+    stats: TimingStats,
+    tempo: f64,
+    ramp: Option<Ramp>,
+    sync_running: bool,
+    random: Random,
+}
+
+/// What the tracks of the current scene did on the last tick, for their followers.
+#[derive(Clone, Copy, Default)]
+struct TrackState {
+    /// The sequence and step whose notes the track triggered.
+    trigger: Option<(usize, usize)>,
+    /// Set when the track started its sequences over.
+    restarted: bool,
+    /// For followers, the notes of the leader counted towards `every`.
+    count: usize,
 }
 
-impl PerformanceController {
-    pub fn new(perf: Performance) -> PerformanceController {
+/// A tempo ramp in progress.
+struct Ramp {
+    from: f64,
+    to: f64,
+    ticks: u64,
+    elapsed: u64,
+}
+
+impl<O: MidiOutput> PerformanceController<O> {
+    pub fn new(perf: Performance, output: O) -> PerformanceController<O> {
         let mut perf_ctrl = PerformanceController {
             scene_index: 0,
             clock_count: 0,
             bar_count: 0,
-            players: HashMap::new(),
+            players: Vec::new(),
+            plan: PerformancePlan::compile(&perf),
             perf,
-            device_manager: DeviceManager::new()
+            track_state: Vec::new(),
+            device_manager: output,
+            pending_actions: Vec::new(),
+            hold_bar: false,
+            loop_scene: false,
+            fill: false,
+            finished: false,
+            muted: HashSet::new(),
+            soloed: HashSet::new(),
+            staged_def: None,
+            applied_def: false,
+            stats: TimingStats::new(),
+            tempo: 0.0,
+            ramp: None,
+            sync_running: false,
+            random: Random::new(0),
         };
+        perf_ctrl.players = (0..perf_ctrl.plan.instruments.len()).map(|_| None).collect();
+        perf_ctrl.track_state = vec![TrackState::default(); perf_ctrl.plan.max_tracks()];
         perf_ctrl.reset();
+        perf_ctrl.send_feedback();
         perf_ctrl
     }
 
-    pub fn update_def(&mut self, def: Performance) {
+    /// Stages a reloaded performance until the boundary set by its `controller.reload`, so that
+    /// instruments aren't swapped while their notes are held.
+    pub fn stage_def(&mut self, def: Performance) {
+        let quantize = def.controller.reload.unwrap_or(Quantize::Bar);
+        self.staged_def = Some((def, quantize));
+        if quantize == Quantize::Immediate {
+            self.commit_staged_def(Quantize::Immediate);
+        } else {
+            log::event(format!("RELOAD STAGED ({:?})", quantize), 0);
+        }
+    }
+
+    fn commit_staged_def(&mut self, boundary: Quantize) {
+        let due = self.staged_def.as_ref().is_some_and(|(_, quantize)| *quantize <= boundary);
+        if due {
+            let (def, _) = self.staged_def.take().unwrap();
+            self.release_all();
+            let diff = diff_performance(&self.perf, &def);
+            let reset_players = self.update_def(def);
+            self.applied_def = true;
+
+            log::success(format!("RELOAD {} change(s)", diff.len()), 0);
+            for entry in diff {
+                log::info(format!("\t{}", entry), 0);
+            }
+            for instrument in reset_players {
+                log::warning(format!("\tRESET player \"{}\"", instrument), 0);
+            }
+        }
+    }
+
+    /// The definition applied since the last call, for the routes, control mappings and inputs that
+    /// are handled by other threads.
+    pub fn take_applied_def(&mut self) -> Option<Performance> {
+        if std::mem::take(&mut self.applied_def) {
+            Some(self.perf.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Replaces the performance definition while keeping the position of all players. Returns the
+    /// instruments whose players were newly created and so started from the beginning.
+    pub fn update_def(&mut self, def: Performance) -> Vec<String> {
+        let plan = PerformancePlan::compile(&def);
+        let mut reset_players: Vec<String> = Vec::new();
+
+        // Players are kept by instrument name, the instruments may have moved in the new definition
+        let mut players: Vec<Option<SequencePlayer>> = (0..plan.instruments.len()).map(|_| None).collect();
+        for (index, player) in self.players.drain(..).enumerate() {
+            if let Some(new_index) = plan.instrument_index(&self.plan.instruments[index].name) {
+                players[new_index] = player;
+            }
+        }
+        self.players = players;
+        self.track_state = vec![TrackState::default(); plan.max_tracks()];
         self.perf = def;
+        self.plan = plan;
 
-        let playlist_index = self.scene_index % self.perf.playlist.len();
-        let scene_name = &self.perf.playlist[playlist_index].to_string();
-
-        let bar_count = self.bar_count.to_owned();
-
-        for scene in self.perf.scenes.iter().filter(|s| &s.name == scene_name) {
-            for track in scene.tracks.iter() {
-                for inst in self.perf.instruments.iter().filter(|i| &i.name == &track.instrument) {
-                    match self.players.get_mut(&track.instrument) {
-                        Some(player) => {
-                            player.instrument = inst.clone();
-                            if track.follow.is_none() {
-                                player.seq_name =
-                                    track.play[bar_count % track.play.len()].to_string();
-                            } else {
-                                // Followers use their own bar count instead
-                                player.seq_name =
-                                    track.play[player.bar_count % track.play.len()].to_string();
-                            }
-                        },
-                        None => {
-                            let seq_name =
-                                track.play[self.bar_count % track.play.len()].to_string();
-                            self.players.insert(
-                                inst.name.to_string(),
-                                SequencePlayer::new(inst.clone(), seq_name)
-                            );
-                        }
+        let bar_count = self.bar_count;
+        if let Some(scene) = self.plan.scene_at(self.scene_index) {
+            for track in &scene.tracks {
+                match &mut self.players[track.instrument] {
+                    Some(player) => {
+                        // Followers use their own bar count instead
+                        let bar = if track.follower { player.bar_count } else { bar_count };
+                        player.sequence = track.sequence(bar, self.fill);
+                    }
+                    None => {
+                        self.players[track.instrument] =
+                            Some(SequencePlayer::new(track.sequence(bar_count, self.fill)));
+                        reset_players.push(self.plan.instruments[track.instrument].name.to_string());
                     }
                 }
             }
         }
+
+        self.update_mute_state();
+        self.send_feedback();
+        reset_players
     }
 
     pub fn reset(&mut self) {
+        if self.sync_running {
+            self.send_sync(0xFC);
+            self.sync_running = false;
+        }
+        self.tempo = self.perf.controller.tempo.unwrap_or(DEFAULT_TEMPO_BPM as f64);
+        self.ramp = None;
+        self.finished = false;
+        self.fill = false;
         self.clock_count = 0;
         self.scene_index = 0;
         self.bar_count = 0;
+        self.pending_actions.clear();
+        self.commit_staged_def(Quantize::Scene);
         self.init_scene();
     }
 
-    pub fn init_scene(&mut self) {
-        let playlist_index = self.scene_index % self.perf.playlist.len();
-        let scene_name = &self.perf.playlist[playlist_index].to_string();
+    /// Hold and loop toggle immediately and take effect at the end of the bar or scene, all other
+    /// actions wait for their quantization boundary.
+    pub fn queue_action(&mut self, action: ControlAction, quantize: Quantize) {
+        match action {
+            ControlAction::Hold => {
+                self.hold_bar = !self.hold_bar;
+                log::event(format!("HOLD {}", if self.hold_bar { "ON" } else { "OFF" }), 0);
+                self.send_feedback();
+            }
+            ControlAction::Loop => {
+                self.loop_scene = !self.loop_scene;
+                log::event(format!("LOOP {}", if self.loop_scene { "ON" } else { "OFF" }), 0);
+                self.send_feedback();
+            }
+            _ => self.pending_actions.push((action, quantize)),
+        }
+    }
+
+    /// Applies all pending actions that are due at `boundary` and returns true if one of them
+    /// changed the scene.
+    fn apply_pending_actions(&mut self, boundary: Quantize) -> bool {
+        let (due, waiting): (Vec<_>, Vec<_>) =
+            self.pending_actions.drain(..).partition(|(_, quantize)| *quantize <= boundary);
+        self.pending_actions = waiting;
+
+        let mut scene_changed = false;
+        for (action, _) in due {
+            scene_changed |= self.apply_action(action);
+        }
+        scene_changed
+    }
+
+    fn apply_action(&mut self, action: ControlAction) -> bool {
+        let playlist_len = self.plan.playlist.len();
+        let playlist_index = self.scene_index % playlist_len;
+        match action {
+            ControlAction::Jump(scene_name) => {
+                match self.plan.find_slot(&scene_name) {
+                    Some(index) => self.change_scene(index),
+                    None => {
+                        log::warning(format!("JUMP unknown scene or section \"{}\"", scene_name), 0);
+                        return false;
+                    }
+                }
+            }
+            ControlAction::Next => self.change_scene(playlist_index + 1),
+            ControlAction::Previous => self.change_scene((playlist_index + playlist_len - 1) % playlist_len),
+            ControlAction::Restart => self.change_scene(0),
+            ControlAction::Mute(instrument) => {
+                toggle(&mut self.muted, instrument);
+                self.update_mute_state();
+                self.send_feedback();
+                return false;
+            }
+            ControlAction::Solo(instrument) => {
+                toggle(&mut self.soloed, instrument);
+                self.update_mute_state();
+                self.send_feedback();
+                return false;
+            }
+            ControlAction::Fill => {
+                self.set_fill(!self.fill);
+                return false;
+            }
+            ControlAction::Hold | ControlAction::Loop => return false,
+        }
+        true
+    }
 
-        for scene in self.perf.scenes.iter().filter(|s| &s.name == scene_name) {
+    /// Switches the tracks of the current scene to or from their fill sequences, keeping their
+    /// position in the bar.
+    fn set_fill(&mut self, fill: bool) {
+        self.fill = fill;
+        let bar_count = self.bar_count;
+        if let Some(scene) = self.plan.scene_at(self.scene_index) {
             for track in &scene.tracks {
-                for inst in self.perf.instruments.iter().filter(|i| &i.name == &track.instrument) {
-                    let seq_name =
-                        track.play[self.bar_count % track.play.len()].to_string();
-                    self.players.insert(
-                        inst.name.to_string(),
-                        SequencePlayer::new(inst.clone(), seq_name)
-                    );
+                if let Some(player) = &mut self.players[track.instrument] {
+                    let bar = if track.follower { player.bar_count } else { bar_count };
+                    player.switch_sequence(track.sequence(bar, fill), &self.plan.instruments[track.instrument]);
                 }
             }
         }
+        log::event(format!("FILL {}", if fill { "ON" } else { "OFF" }), 0);
+        self.send_feedback();
     }
 
-    pub fn next_bar(&mut self) {
-        self.bar_count += 1;
+    /// Applies the mute and solo state to all players. The state is kept by instrument name, so it
+    /// carries over scene changes and reloads of the performance.
+    fn update_mute_state(&mut self) {
+        for (index, instrument) in self.plan.instruments.iter().enumerate() {
+            if let Some(player) = &mut self.players[index] {
+                let audible = is_audible(&self.muted, &self.soloed, &instrument.name);
+                player.set_muted(!audible, instrument, &mut self.device_manager);
+            }
+        }
+    }
+
+    fn send_feedback(&mut self) {
+        let mappings = self.perf.control.as_ref().map_or(Vec::new(), |c| c.to_vec());
+        for mapping in mappings {
+            let active = match &mapping.action {
+                ControlAction::Mute(instrument) => self.muted.contains(instrument),
+                ControlAction::Solo(instrument) => self.soloed.contains(instrument),
+                ControlAction::Hold => self.hold_bar,
+                ControlAction::Loop => self.loop_scene,
+                ControlAction::Fill => self.fill,
+                _ => continue,
+            };
+            if let Some(message) = feedback_message(&mapping, active) {
+                self.device_manager.write_messages(&mapping.device, &[message]);
+            }
+        }
+    }
+
+    pub fn output(&mut self) -> &mut O {
+        &mut self.device_manager
+    }
+
+    /// The tempo set by the controller and the scenes, which the internal clock plays at.
+    pub fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    /// Replaces the generator for random follow actions, which otherwise has a fixed seed.
+    pub fn set_random(&mut self, random: Random) {
+        self.random = random;
+    }
 
-        let playlist_index = self.scene_index % self.perf.playlist.len();
-        let scene_name = &self.perf.playlist[playlist_index].to_string();
-        let mut next_scene = false;
+    fn send_sync(&mut self, status: u8) {
+        for device in self.perf.controller.sync.iter().flatten() {
+            self.device_manager.write_messages(device, &[realtime(status)]);
+        }
+    }
 
-        for scene in self.perf.scenes.iter().filter(|s| &s.name == scene_name) {
-            let first_track = &scene.tracks[0];
-            if self.bar_count >= first_track.play.len() {
-                next_scene = true;
+    /// Sends note-offs for all notes that are still held by any player.
+    pub fn release_all(&mut self) {
+        for (index, instrument) in self.plan.instruments.iter().enumerate() {
+            if let Some(player) = &mut self.players[index] {
+                player.note_off_all(instrument, &mut self.device_manager);
             }
         }
+    }
 
-        if next_scene {
-            self.scene_index += 1;
-            self.bar_count = 0;
-            self.init_scene();
+    fn change_scene(&mut self, scene_index: usize) {
+        self.release_all();
+
+        self.scene_index = scene_index;
+        self.bar_count = 0;
+        self.finished = false;
+        self.init_scene();
+        self.log_scene();
+    }
+
+    fn log_scene(&self) {
+        let slot = self.plan.slot_at(self.scene_index);
+        let section = slot.section.as_ref().map_or(String::new(), |s| format!(" in \"{}\"", s));
+        let position = format!("{}/{}", self.scene_index, self.plan.playlist.len());
+        log::event(format!("SCENE {} \"{}\"{} ", position, slot.name, section), 0);
+    }
+
+    /// The number of bars of the current playlist slot, or `None` if its scene doesn't exist.
+    fn scene_length(&self) -> Option<usize> {
+        let slot = self.plan.slot_at(self.scene_index);
+        slot.scene.map(|_| slot.length)
+    }
+
+    /// Moves on to the slot picked by the follow actions of the scene that ended. Returns false if
+    /// the playlist stopped.
+    fn follow_scene(&mut self) -> bool {
+        let len = self.plan.playlist.len();
+        let index = self.scene_index % len;
+        let action = match self.plan.scene_at(self.scene_index) {
+            Some(scene) if !scene.follow.is_empty() => {
+                let pick = self.random.weighted(scene.follow.iter().map(|f| f.weight()));
+                scene.follow[pick].action().clone()
+            }
+            _ => FollowAction::Next,
+        };
+
+        match action {
+            FollowAction::Next => return self.next_slot(),
+            FollowAction::Previous => self.scene_index = (index + len - 1) % len,
+            FollowAction::First => self.scene_index = 0,
+            FollowAction::Random => self.scene_index = self.random.below(len),
+            FollowAction::RandomOther => {
+                let name = &self.plan.playlist[index].name;
+                let others = self.plan.playlist.iter().enumerate().filter(|(_, s)| &s.name != name);
+                let pick = self.random.below(others.clone().count());
+                if let Some((other, _)) = others.clone().nth(pick) {
+                    self.scene_index = other;
+                }
+            }
+            FollowAction::Jump(name) => match self.plan.find_slot(&name) {
+                Some(slot) => self.scene_index = slot,
+                None => {
+                    log::warning(format!("FOLLOW unknown scene or section \"{}\"", name), 0);
+                    return self.next_slot();
+                }
+            },
+            FollowAction::Loop => {}
+        }
+        true
+    }
+
+    /// Moves on to the next slot of the playlist, or applies the `end` of the performance after
+    /// the last one. Returns false if the playlist stopped.
+    fn next_slot(&mut self) -> bool {
+        let last = self.scene_index % self.plan.playlist.len() == self.plan.playlist.len() - 1;
+        match self.perf.end {
+            Some(PlaylistEnd::Stop) if last => {
+                self.release_all();
+                if self.sync_running {
+                    self.send_sync(0xFC);
+                    self.sync_running = false;
+                }
+                self.finished = true;
+                log::event(String::from("END"), 0);
+                return false;
+            }
+            Some(PlaylistEnd::Hold) if last => {}
+            _ => self.scene_index += 1,
+        }
+        true
+    }
+
+    /// Restarts the players of the current scene and applies its tempo. A ramp that is still in
+    /// progress carries on, unless the scene sets a tempo or a ramp of its own.
+    pub fn init_scene(&mut self) {
+        for state in self.track_state.iter_mut() {
+            *state = TrackState::default();
+        }
+        if let Some(scene) = self.plan.scene_at(self.scene_index) {
+            if let Some(tempo) = scene.tempo {
+                self.tempo = tempo;
+                self.ramp = None;
+                log::event(format!("TEMPO {:.1}", tempo), 0);
+            }
+            if let Some((to, ticks)) = scene.ramp {
+                self.ramp = Some(Ramp {
+                    from: self.tempo,
+                    to,
+                    ticks,
+                    elapsed: 0,
+                });
+                log::event(format!("RAMP {:.1} -> {:.1}", self.tempo, to), 0);
+            }
+            for (device, message) in &scene.sysex {
+                self.device_manager.write_sysex(device, message);
+            }
+            for track in &scene.tracks {
+                let sequence = track.sequence(self.bar_count, self.fill);
+                match &mut self.players[track.instrument] {
+                    Some(player) => player.restart(sequence),
+                    None => self.players[track.instrument] = Some(SequencePlayer::new(sequence)),
+                }
+            }
+        }
+
+        self.update_mute_state();
+    }
+
+    pub fn next_bar(&mut self) {
+        let scene_end = !self.hold_bar && self.scene_length().is_some_and(|l| self.bar_count + 1 >= l);
+        let boundary = if scene_end || self.finished { Quantize::Scene } else { Quantize::Bar };
+
+        // Fills last until the end of the bar, unless another fill is due now
+        if self.fill {
+            self.set_fill(false);
         }
 
-        let playlist_index = self.scene_index % self.perf.playlist.len();
-        let scene_name = &self.perf.playlist[playlist_index].to_string();
+        self.commit_staged_def(boundary);
+        if self.apply_pending_actions(boundary) || self.finished {
+            return;
+        }
 
+        if !self.hold_bar {
+            self.bar_count += 1;
+        }
 
-        let bar_count = self.bar_count.to_owned();
+        let next_scene = self.scene_length().is_some_and(|l| self.bar_count >= l);
+        if next_scene {
+            if !self.loop_scene && !self.follow_scene() {
+                return;
+            }
+            self.bar_count = 0;
+            self.init_scene();
+        }
 
         // Advance non-follower sequence players
-        for scene in self.perf.scenes.iter().filter(|s| &s.name == scene_name) {
-            for track in scene.tracks.iter().filter(|t| t.follow.is_none()) {
-                self.players.get_mut(&track.instrument).map(|player| {
+        let bar_count = self.bar_count;
+        let fill = self.fill;
+        if let Some(scene) = self.plan.scene_at(self.scene_index) {
+            for track in scene.tracks.iter().filter(|t| !t.follower) {
+                if let Some(player) = &mut self.players[track.instrument] {
                     player.reset();
-                    player.seq_name =
-                        track.play[bar_count  % track.play.len()].to_string();
-                });
+                    player.sequence = track.sequence(bar_count, fill);
+                }
             }
         }
 
         if next_scene {
-            log::event(format!("SCENE {}/{} \"{}\" ", self.scene_index, self.perf.playlist.len(), scene_name), 0);
+            self.log_scene();
         }
     }
 
-    pub fn clock(&mut self, tick_count: u64) {
+    /// Moves the tempo along the ramp once per MIDI clock, so that the internal clock isn't
+    /// updated on every tick.
+    fn advance_ramp(&mut self, tick_count: u64) {
+        let done = match &mut self.ramp {
+            Some(ramp) => {
+                ramp.elapsed += 1;
+                if tick_count == 0 || ramp.elapsed >= ramp.ticks {
+                    let progress = (ramp.elapsed as f64 / ramp.ticks as f64).min(1.0);
+                    self.tempo = ramp.from + (ramp.to - ramp.from) * progress;
+                }
+                ramp.elapsed >= ramp.ticks
+            }
+            None => false,
+        };
+        if done {
+            self.ramp = None;
+        }
+    }
 
+    /// Stats recorded since they were last taken, counting the ticks whose position within the
+    /// incoming clock didn't match the position in the bar.
+    pub fn take_stats(&mut self) -> TimingStats {
+        std::mem::replace(&mut self.stats, TimingStats::new())
+    }
+
+    pub fn clock(&mut self, tick_count: u64) {
+        if self.clock_count as u64 % CLOCK_MULTIPLIER != tick_count {
+            self.stats.phase_errors += 1;
+        }
 
-        let quarter_count = self.clock_count as u64 % CLOCK_MULTIPLIER;
-        if quarter_count != tick_count {
-            println!("qc={} != tc={}", quarter_count, tick_count);
+        if tick_count == 0 && !self.finished {
+            if !self.sync_running {
+                self.send_sync(0xFA);
+                self.sync_running = true;
+            }
+            self.send_sync(0xF8);
         }
+        self.advance_ramp(tick_count);
 
-        if self.clock_count % TICKS_PER_MEASURE as usize == 0 {
+        if self.clock_count.is_multiple_of(TICKS_PER_MEASURE as usize) {
             // println!("ticker per bar = {}", self.clock_count);
             self.clock_count = 0;
             self.next_bar();
+        } else if !self.pending_actions.is_empty() || self.staged_def.is_some() {
+            let boundary = if self.clock_count.is_multiple_of(TICKS_PER_BEAT as usize) {
+                Quantize::Beat
+            } else {
+                Quantize::Immediate
+            };
+            self.commit_staged_def(boundary);
+            // Scene changes in the middle of a bar restart the bar where they are applied, but keep the
+            // position within the incoming clock so that the bars stay on its grid
+            if self.apply_pending_actions(boundary) {
+                self.clock_count %= CLOCK_MULTIPLIER as usize;
+            }
         }
 
-        let playlist_index = self.scene_index % self.perf.playlist.len();
-        let scene_name = &self.perf.playlist[playlist_index].to_string();
+        let device_manager = &mut self.device_manager;
+        let instruments = &self.plan.instruments;
+        let players = &mut self.players;
+        let track_state = &mut self.track_state;
+        let fill = self.fill;
+        let bar_start = self.clock_count == 0;
+        let bar_count = self.bar_count;
 
-        let mut device_manager = &mut self.device_manager;
-
-        for scene in self.perf.scenes.iter().filter(|s| &s.name == scene_name) {
-            let mut note_played: HashMap<String, bool> = HashMap::new();
+        let scene = if self.finished { None } else { self.plan.scene_at(self.scene_index) };
+        if let Some(scene) = scene {
             // First clock all the non-followers
-            for track in scene.tracks.iter().filter(|t| t.follow.is_none()) {
-                self.players.get_mut(&track.instrument).map(|player| {
-                    note_played.insert(track.instrument.to_string(), player.clock(&mut device_manager));
-                });
+            for (index, track) in scene.tracks.iter().enumerate().filter(|(_, t)| !t.follower) {
+                let instrument = &instruments[track.instrument];
+                let state = &mut track_state[index];
+                state.restarted = bar_start && bar_count.is_multiple_of(track.play.len());
+                let player = players[track.instrument].as_mut();
+                state.trigger = player.and_then(|p| p.clock(instrument, device_manager, fill));
             }
-            // Then clock all the followers
-            for track in scene.tracks.iter().filter(|t| t.follow.is_some()) {
-                self.players.get_mut(&track.instrument).map(|player| {
-                    let follow_name = track.follow.as_ref().unwrap_or(&String::from("")).to_string();
-                    let note_was_played = note_played.get(&follow_name).unwrap_or(&false);
-                    if *note_was_played {
-                        player.next_bar(&mut device_manager);
-                        player.seq_name =
-                            track.play[player.bar_count  % track.play.len()].to_string();
+            // Then clock the followers, each one after the track it follows
+            for &index in &scene.follow_order {
+                let track = &scene.tracks[index];
+                let instrument = &instruments[track.instrument];
+                let leader = track.leader.map(|l| (&instruments[scene.tracks[l].instrument], track_state[l]));
+                let state = &mut track_state[index];
+                state.restarted = false;
+                state.trigger = None;
+
+                if let Some(player) = &mut players[track.instrument] {
+                    match leader {
+                        Some((_, leader_state)) if track.reset && leader_state.restarted => {
+                            player.next_bar(instrument, device_manager);
+                            player.bar_count = 0;
+                            player.sequence = track.sequence(0, fill);
+                            state.count = 0;
+                            state.restarted = true;
+                        }
+                        Some((leader, leader_state)) if plays_pitch(leader, leader_state.trigger, track.pitch) => {
+                            state.count += 1;
+                            if state.count >= track.every {
+                                state.count = 0;
+                                player.next_bar(instrument, device_manager);
+                                player.sequence = track.sequence(player.bar_count, fill);
+                                state.restarted = player.bar_count.is_multiple_of(track.play.len());
+                            }
+                        }
+                        _ => {}
                     }
-                    player.clock(&mut device_manager);
-                });
+                    state.trigger = player.clock(instrument, device_manager, fill);
+                }
             }
         }
 
         self.clock_count += 1;
     }
 }
+
+/// Whether a step triggered by the instrument plays the pitch, or any note without a pitch.
+fn plays_pitch(instrument: &InstrumentPlan, trigger: Option<(usize, usize)>, pitch: Option<u8>) -> bool {
+    match (trigger, pitch) {
+        (Some((sequence, step)), Some(pitch)) => {
+            let step = instrument.sequences[sequence].steps[step].as_ref();
+            step.is_some_and(|s| s.notes.contains(&pitch))
+        }
+        (trigger, None) => trigger.is_some(),
+        (None, _) => false,
+    }
+}
+
+fn toggle(set: &mut HashSet<String>, name: String) {
+    if !set.remove(&name) {
+        set.insert(name);
+    }
+}
+
+fn is_audible(muted: &HashSet<String>, soloed: &HashSet<String>, instrument: &String) -> bool {
+    if soloed.is_empty() {
+        !muted.contains(instrument)
+    } else {
+        soloed.contains(instrument)
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{CLOCK_MULTIPLIER, TICKS_PER_MEASURE};
+    use crate::midi::MidiRecorder;
+    use crate::models::{ControlAction, Performance, Quantize};
+    use crate::performance::PerformanceController;
+
+    fn clock(perf_ctrl: &mut PerformanceController<MidiRecorder>, from: u64, ticks: u64) {
+        for tick in from..from + ticks {
+            perf_ctrl.clock(tick % CLOCK_MULTIPLIER);
+        }
+    }
+
+    #[test]
+    fn test_applied_def() {
+        let yaml_text = r#"
+controller: { device: control, channel: 1, reload: bar }
+playlist: [ one ]
+scenes: [ { name: one, tracks: [ { instrument: bass, play: [ A ] } ] } ]
+instruments: [ { name: bass, device: synth, channel: 1, sequences: [ { name: A, steps: [ { pitch: [ C2 ] } ] } ] } ]
+"#;
+        let perf = serde_yaml::from_str::<Performance>(yaml_text).unwrap();
+        let mut perf_ctrl = PerformanceController::new(perf.clone(), MidiRecorder::new());
+        clock(&mut perf_ctrl, 0, 100);
+
+        // Routes and control mappings are handed over with the staged performance at the bar
+        let mut def = perf.clone();
+        def.routes = Some(vec![]);
+        perf_ctrl.stage_def(def.clone());
+        clock(&mut perf_ctrl, 100, 100);
+        assert_eq!(perf_ctrl.take_applied_def(), None);
+        clock(&mut perf_ctrl, 200, TICKS_PER_MEASURE - 200 + 1);
+        assert_eq!(perf_ctrl.take_applied_def(), Some(def.clone()));
+        assert_eq!(perf_ctrl.take_applied_def(), None);
+
+        def.controller.reload = Some(Quantize::Immediate);
+        perf_ctrl.stage_def(def.clone());
+        assert_eq!(perf_ctrl.take_applied_def(), Some(def));
+    }
+
+    #[test]
+    fn test_immediate_jump_phase() {
+        let yaml_text = r#"
+controller: { device: control, channel: 1 }
+playlist: [ one, two ]
+scenes:
+  - { name: one, tracks: [ { instrument: bass, play: [ A ] } ] }
+  - { name: two, tracks: [ { instrument: bass, play: [ A ] } ] }
+instruments: [ { name: bass, device: synth, channel: 1, sequences: [ { name: A, steps: [ { pitch: [ C2 ] } ] } ] } ]
+"#;
+        let perf = serde_yaml::from_str::<Performance>(yaml_text).unwrap();
+        let mut perf_ctrl = PerformanceController::new(perf, MidiRecorder::new());
+        clock(&mut perf_ctrl, 0, 1000);
+
+        // The jump lands between two incoming clocks, the following bars must stay on their grid
+        perf_ctrl.queue_action(ControlAction::Next, Quantize::Immediate);
+        clock(&mut perf_ctrl, 1000, 2 * TICKS_PER_MEASURE);
+        assert_eq!(perf_ctrl.take_stats().phase_errors, 0);
+    }
+}
@@ -18,14 +18,15 @@
  */
 use std::ffi::OsStr;
 use std::fs;
-use std::sync::mpsc::Sender;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use notify::event::DataChange::Content;
-use notify::event::ModifyKind::Data;
-use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
 
+use crate::config::FILE_WATCH_DEBOUNCE_MS;
+use crate::log;
 use crate::models::Performance;
 
 // Performance File --------------------------------------------------------------------------------
@@ -33,36 +34,270 @@ use crate::models::Performance;
 pub fn load_performance_file(file_path: &OsStr) -> Result<Performance, std::io::Error> {
     match fs::read_to_string(file_path) {
         Ok(yaml_text) => match serde_yaml::from_str::<Performance>(&yaml_text) {
-            Ok(perf) => Ok(perf),
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
+            Ok(perf) => match perf.validate() {
+                Ok(()) => Ok(perf),
+                Err(e) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+            },
+            Err(e) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format_parse_error(&yaml_text, &e),
+            )),
         },
         Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
     }
 }
 
-pub fn start_file_watcher(file_path: &String, perf_send: Sender<Performance>) {
-    let watch_file_path = file_path.to_owned();
-    thread::spawn(|| {
-        let mut watcher: RecommendedWatcher =
-            Watcher::new_immediate(move |res: Result<notify::Event, notify::Error>| match res {
-                Ok(event) => {
-                    if event.kind == notify::EventKind::Modify(Data(Content)) {
-                        match load_performance_file(event.paths[0].as_os_str()) {
-                            Ok(perf) => perf_send.send(perf).unwrap(),
-                            Err(e) => println!("Error parsing file: {}", e),
+/// Formats a parse error with the offending line of the file and a marker below its column.
+pub fn format_parse_error(yaml_text: &str, error: &serde_yaml::Error) -> String {
+    match error.location() {
+        Some(location) if location.line() > 0 => {
+            let line = yaml_text.lines().nth(location.line() - 1).unwrap_or("");
+            let gutter = location.line().to_string().len();
+            format!(
+                "{}\n{:>w$} |\n{} | {}\n{:>w$} | {:>c$}",
+                error,
+                "",
+                location.line(),
+                line,
+                "",
+                "^",
+                w = gutter,
+                c = location.column(),
+            )
+        }
+        _ => error.to_string(),
+    }
+}
+
+/// Watches the performance file and sends it whenever it was changed and parses successfully.
+///
+/// Many editors save by writing a temp file and renaming it over the original, which replaces the
+/// file's inode. To catch those saves the parent directory is watched as well, events are matched
+/// by file name and bursts of events are debounced into a single reload. When the inode of the
+/// file changed the watch on the file itself is re-established. A watcher that fails is created
+/// again until that succeeds, and the file is reloaded then in case it changed in the meantime.
+pub fn start_file_watcher(file_path: &str, perf_send: crossbeam_channel::Sender<Performance>) {
+    let file_path = PathBuf::from(file_path);
+    let delay = Duration::from_millis(FILE_WATCH_DEBOUNCE_MS);
+    thread::spawn(move || {
+        let (event_send, event_recv) = channel();
+        let mut watcher: Option<RecommendedWatcher> = None;
+        let mut watch_failed = false;
+        let mut watched_id = file_id(&file_path);
+        let mut debounce = Debounce::new(delay);
+
+        loop {
+            if watcher.is_none() {
+                match create_watcher(&file_path, event_send.clone()) {
+                    Ok(new_watcher) => {
+                        watcher = Some(new_watcher);
+                        watched_id = file_id(&file_path);
+                        if watch_failed {
+                            log::success(format!("WATCHING {:?} again", file_path), 0);
+                            debounce.event(Instant::now());
+                        }
+                        watch_failed = false;
+                    }
+                    Err(e) => {
+                        // Only report the first failure, it is retried after every debounce delay
+                        if !watch_failed {
+                            log::error(format!("ERROR watching {:?}, retrying: {}", file_path, e), 0);
                         }
+                        watch_failed = true;
                     }
                 }
-                Err(e) => println!("watch error: {:?}", e),
-            })
-            .expect("failed to create watcher");
+            }
 
-        watcher
-            .watch(watch_file_path, RecursiveMode::Recursive)
-            .unwrap();
-        loop {
-            // Keep the thread running so that we can watch the file indefinitely
-            thread::sleep(Duration::from_millis(60_000));
+            match event_recv.recv_timeout(delay) {
+                Ok(Ok(event)) => {
+                    if is_file_event(&event, &file_path) {
+                        debounce.event(Instant::now());
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::warning(format!("WATCH ERROR {:?}: {}", file_path, e), 0);
+                    watcher = None;
+                    watch_failed = true;
+                }
+                Err(_) => {}
+            }
+
+            if debounce.is_due(Instant::now()) {
+                if !file_path.exists() {
+                    log::warning(format!("FILE REMOVED {:?}, waiting for it to reappear", file_path), 0);
+                    continue;
+                }
+
+                let id = file_id(&file_path);
+                if needs_rewatch(watched_id, id) {
+                    if let Some(watcher) = &mut watcher {
+                        // Unwatching fails if the old inode is already gone, which is expected here
+                        let _ = watcher.unwatch(&file_path);
+                        if let Err(e) = watcher.watch(&file_path, RecursiveMode::NonRecursive) {
+                            log::warning(format!("WATCH ERROR {:?}: {}", file_path, e), 0);
+                        }
+                    }
+                    watched_id = id;
+                }
+
+                match load_performance_file(file_path.as_os_str()) {
+                    Ok(perf) => perf_send.send(perf).unwrap(),
+                    Err(e) => {
+                        let text = format!("ERROR loading {:?}, keeping the last good performance\n{}", file_path, e);
+                        log::error(text, 0);
+                    }
+                }
+            }
         }
     });
 }
+
+fn create_watcher(
+    file_path: &Path,
+    event_send: Sender<Result<notify::Event, notify::Error>>,
+) -> Result<RecommendedWatcher, notify::Error> {
+    let mut watcher: RecommendedWatcher = Watcher::new_immediate(move |res| {
+        let _ = event_send.send(res);
+    })?;
+
+    watcher.watch(parent_dir(file_path), RecursiveMode::NonRecursive)?;
+    // The file may not exist yet, the directory watch catches it being created
+    if let Err(e) = watcher.watch(file_path, RecursiveMode::NonRecursive) {
+        log::warning(format!("WATCH ERROR {:?}: {}", file_path, e), 0);
+    }
+    Ok(watcher)
+}
+
+/// Collects a burst of events into one, which is due once no event arrived for the delay.
+struct Debounce {
+    delay: Duration,
+    last_event: Option<Instant>,
+}
+
+impl Debounce {
+    fn new(delay: Duration) -> Debounce {
+        Debounce { delay, last_event: None }
+    }
+
+    fn event(&mut self, now: Instant) {
+        self.last_event = Some(now);
+    }
+
+    /// Whether the burst is over, which starts the next one.
+    fn is_due(&mut self, now: Instant) -> bool {
+        match self.last_event {
+            Some(last_event) if now.saturating_duration_since(last_event) >= self.delay => {
+                self.last_event = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parent_dir(file_path: &Path) -> PathBuf {
+    match file_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+fn is_file_event(event: &notify::Event, file_path: &Path) -> bool {
+    let relevant_kind = match event.kind {
+        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) | EventKind::Any => true,
+        EventKind::Access(_) | EventKind::Other => false,
+    };
+    relevant_kind && event.paths.iter().any(|p| p.file_name() == file_path.file_name())
+}
+
+/// Whether the watch on the file has to be re-established, because the file was replaced by
+/// another inode or its inode is unknown.
+fn needs_rewatch(watched_id: Option<u64>, id: Option<u64>) -> bool {
+    id.is_none() || id != watched_id
+}
+
+#[cfg(unix)]
+fn file_id(file_path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(file_path).ok().map(|m| m.ino())
+}
+
+#[cfg(not(unix))]
+fn file_id(_file_path: &Path) -> Option<u64> {
+    None
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    use notify::event::{AccessKind, CreateKind, ModifyKind};
+    use notify::{Event, EventKind};
+
+    use crate::models::Performance;
+    use crate::performance_file::{file_id, format_parse_error, is_file_event, needs_rewatch, Debounce};
+
+    #[test]
+    fn test_format_parse_error() {
+        let yaml_text = "controller:\n  device: x\n  channel: [ 1 ]\n";
+        let error = serde_yaml::from_str::<Performance>(yaml_text).unwrap_err();
+        let message = format_parse_error(yaml_text, &error);
+        let lines = message.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].contains("line 3"));
+        assert_eq!(lines[2], "3 |   channel: [ 1 ]");
+        assert_eq!(lines[3], "  |            ^");
+    }
+
+    #[test]
+    fn test_is_file_event() {
+        let file_path = PathBuf::from("data/live.yaml");
+        let event = |kind: EventKind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+
+        // Editors rename temp files over the original, so paths are matched by file name only
+        assert!(is_file_event(&event(EventKind::Modify(ModifyKind::Any), "/home/x/data/live.yaml"), &file_path));
+        assert!(is_file_event(&event(EventKind::Create(CreateKind::File), "live.yaml"), &file_path));
+        assert!(!is_file_event(&event(EventKind::Modify(ModifyKind::Any), "data/live.yaml~"), &file_path));
+        assert!(!is_file_event(&event(EventKind::Access(AccessKind::Any), "data/live.yaml"), &file_path));
+    }
+
+    #[test]
+    fn test_debounce() {
+        let start = Instant::now();
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        let mut debounce = Debounce::new(Duration::from_millis(100));
+
+        assert!(!debounce.is_due(at(0)));
+        debounce.event(at(0));
+        debounce.event(at(60));
+        assert!(!debounce.is_due(at(120)));
+        assert!(debounce.is_due(at(160)));
+        assert!(!debounce.is_due(at(300)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_needs_rewatch_after_rename() {
+        let dir = std::env::temp_dir().join(format!("cfgseq-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file_path = dir.join("live.yaml");
+        fs::write(&file_path, "a").unwrap();
+        let watched_id = file_id(&file_path);
+
+        // Writing in place keeps the inode, renaming a temp file over the original replaces it
+        fs::write(&file_path, "b").unwrap();
+        assert!(!needs_rewatch(watched_id, file_id(&file_path)));
+        let temp_path = dir.join("live.yaml.tmp");
+        fs::write(&temp_path, "c").unwrap();
+        fs::rename(&temp_path, &file_path).unwrap();
+        assert!(needs_rewatch(watched_id, file_id(&file_path)));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(needs_rewatch(watched_id, file_id(&file_path)));
+    }
+}
@@ -0,0 +1,492 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+extern crate portmidi;
+
+use portmidi::MidiMessage;
+
+use crate::config::{DEFAULT_VELOCITY, TICKS_PER_MEASURE};
+use crate::midi::{control_change_14bit, nrpn, parse_midi_note, pitch_bend, rpn};
+use crate::models::{FollowChoice, Instrument, Performance, PlaylistEntry, Scene, Sequence, SequenceStep, TrackFollow};
+
+// Performance Plan --------------------------------------------------------------------------------
+
+/// A performance compiled for playback. Scene, instrument and sequence names are resolved to
+/// indices and notes are parsed up front, so that playing a tick needs no lookups or allocations.
+/// Names that don't resolve are kept as `None` and play nothing, like in the performance itself.
+pub struct PerformancePlan {
+    /// The playlist with its sections and repeats expanded.
+    pub playlist: Vec<PlaylistSlot>,
+    pub scenes: Vec<ScenePlan>,
+    pub instruments: Vec<InstrumentPlan>,
+}
+
+pub struct PlaylistSlot {
+    pub name: String,
+    pub scene: Option<usize>,
+    /// The number of bars set by the playlist entry, or else by the scene.
+    pub length: usize,
+    /// The section this slot was expanded from.
+    pub section: Option<String>,
+}
+
+pub struct ScenePlan {
+    pub name: String,
+    /// The number of bars set by the scene, or else the number of sequences of the first track.
+    pub length: usize,
+    /// Tracks whose instrument exists, in the order of the scene.
+    pub tracks: Vec<TrackPlan>,
+    pub tempo: Option<f64>,
+    /// The tempo to ramp to and the number of ticks to get there.
+    pub ramp: Option<(f64, u64)>,
+    /// The follow actions, empty to go to the next entry of the playlist.
+    pub follow: Vec<FollowChoice>,
+    /// The followers, ordered so that every track comes after the track it follows.
+    pub follow_order: Vec<usize>,
+    /// The devices and SysEx messages to send when the scene starts.
+    pub sysex: Vec<(String, Vec<u8>)>,
+}
+
+pub struct TrackPlan {
+    pub instrument: usize,
+    pub follower: bool,
+    /// For followers, the track of the same scene whose notes advance this one. Followers that
+    /// follow each other in a circle have none.
+    pub leader: Option<usize>,
+    /// Advances on every nth note of the leader.
+    pub every: usize,
+    /// Only counts the notes of the leader with this pitch.
+    pub pitch: Option<u8>,
+    /// Goes back to the first sequence when the leader starts its sequences over.
+    pub reset: bool,
+    pub play: Vec<Option<usize>>,
+    /// The sequence played during fills, if the track has one that exists.
+    pub fill: Option<usize>,
+}
+
+impl TrackPlan {
+    /// The sequence to play in a bar, which is the fill sequence during fills.
+    pub fn sequence(&self, bar: usize, fill: bool) -> Option<usize> {
+        match self.fill {
+            Some(sequence) if fill => Some(sequence),
+            _ => self.play[bar % self.play.len()],
+        }
+    }
+}
+
+pub struct InstrumentPlan {
+    pub name: String,
+    pub device: String,
+    pub channel: u8,
+    pub data: Vec<DataLane>,
+    pub sequences: Vec<SequencePlan>,
+}
+
+pub struct DataLane {
+    pub device: String,
+    pub channel: u8,
+    pub control: u8,
+}
+
+pub struct SequencePlan {
+    pub name: String,
+    /// The clock ticks between the note-on and note-off halves of each step.
+    pub ticks_per_step: usize,
+    pub steps: Vec<Option<StepPlan>>,
+}
+
+pub struct StepPlan {
+    pub notes: Vec<u8>,
+    pub velocity: u8,
+    pub program: Option<u8>,
+    /// Pitch bend, parameter and 14-bit controller messages sent before the notes.
+    pub controls: Vec<MidiMessage>,
+    pub aftertouch: Option<u8>,
+    pub poly_aftertouch: Option<u8>,
+    /// SysEx messages and the devices they go to instead of the instrument's.
+    pub sysex: Vec<(Option<String>, Vec<u8>)>,
+    /// Whether the step plays only during fills or only outside of them.
+    pub fill: Option<bool>,
+    /// One value per data lane of the instrument, values without a lane are dropped.
+    pub data: Option<Vec<u8>>,
+}
+
+impl PerformancePlan {
+    pub fn compile(perf: &Performance) -> PerformancePlan {
+        let instruments = perf.instruments.iter().map(compile_instrument).collect::<Vec<_>>();
+        let scenes = perf.scenes.iter().map(|s| compile_scene(s, &instruments)).collect::<Vec<_>>();
+        let mut playlist: Vec<PlaylistSlot> = Vec::new();
+        for entry in &perf.playlist {
+            match entry.section_name().and_then(|name| perf.find_section(name)) {
+                Some(section) => {
+                    for _ in 0..entry.repeat_count() {
+                        for section_entry in &section.playlist {
+                            compile_entry(&mut playlist, section_entry, Some(&section.name), &scenes);
+                        }
+                    }
+                }
+                None => compile_entry(&mut playlist, entry, None, &scenes),
+            }
+        }
+
+        PerformancePlan {
+            playlist,
+            scenes,
+            instruments,
+        }
+    }
+
+    /// The slot at a position of the playlist, which wraps around at its end.
+    pub fn slot_at(&self, scene_index: usize) -> &PlaylistSlot {
+        &self.playlist[scene_index % self.playlist.len()]
+    }
+
+    /// The scene at a position of the playlist, which wraps around at its end.
+    pub fn scene_at(&self, scene_index: usize) -> Option<&ScenePlan> {
+        self.slot_at(scene_index).scene.map(|index| &self.scenes[index])
+    }
+
+    /// The first slot that plays the scene, or else the first slot of the section.
+    pub fn find_slot(&self, name: &str) -> Option<usize> {
+        self.playlist
+            .iter()
+            .position(|s| s.name == name)
+            .or_else(|| self.playlist.iter().position(|s| s.section.as_ref().is_some_and(|n| n == name)))
+    }
+
+    pub fn instrument_index(&self, name: &str) -> Option<usize> {
+        self.instruments.iter().position(|i| i.name == name)
+    }
+
+    pub fn max_tracks(&self) -> usize {
+        self.scenes.iter().map(|s| s.tracks.len()).max().unwrap_or(0)
+    }
+}
+
+/// Adds a slot for every repeat of the entry. Sections within sections aren't valid and are skipped.
+fn compile_entry(
+    playlist: &mut Vec<PlaylistSlot>,
+    entry: &PlaylistEntry,
+    section: Option<&String>,
+    scenes: &[ScenePlan],
+) {
+    let name = match entry.scene_name() {
+        Some(name) => name,
+        None => return,
+    };
+    let scene = scenes.iter().position(|s| &s.name == name);
+    let length = entry.bar_count().unwrap_or_else(|| scene.map_or(0, |index| scenes[index].length));
+    for _ in 0..entry.repeat_count() {
+        playlist.push(PlaylistSlot {
+            name: name.to_string(),
+            scene,
+            length,
+            section: section.cloned(),
+        });
+    }
+}
+
+fn compile_scene(scene: &Scene, instruments: &[InstrumentPlan]) -> ScenePlan {
+    let tracks = scene
+        .tracks
+        .iter()
+        .filter_map(|track| {
+            let instrument = instruments.iter().position(|i| i.name == track.instrument)?;
+            let sequence = |name: &String| instruments[instrument].sequences.iter().position(|s| &s.name == name);
+            let play: Vec<Option<usize>> = track.play.iter().map(sequence).collect();
+            Some((track, instrument, play, track.fill.as_ref().and_then(sequence)))
+        })
+        .collect::<Vec<_>>();
+
+    // Followers are advanced by the notes of the last leading track that plays their instrument, or
+    // else by the last other follower that plays it
+    let leader = |index: usize, follow: &TrackFollow| {
+        let plays = |i: &usize| *i != index && &instruments[tracks[*i].1].name == follow.instrument();
+        let leading = (0..tracks.len()).rev().filter(plays).find(|i| tracks[*i].0.follow.is_none());
+        leading.or_else(|| (0..tracks.len()).rev().find(plays))
+    };
+
+    let mut tracks = tracks
+        .iter()
+        .enumerate()
+        .map(|(index, (track, instrument, play, fill))| TrackPlan {
+            instrument: *instrument,
+            follower: track.follow.is_some(),
+            leader: track.follow.as_ref().and_then(|f| leader(index, f)),
+            every: track.follow.as_ref().map_or(1, |f| f.every()),
+            pitch: track.follow.as_ref().and_then(|f| f.pitch()).map(parse_midi_note),
+            reset: track.follow.as_ref().is_some_and(|f| f.reset()),
+            play: play.to_vec(),
+            fill: *fill,
+        })
+        .collect::<Vec<_>>();
+    let follow_order = order_followers(&mut tracks);
+
+    ScenePlan {
+        name: scene.name.to_string(),
+        length: scene.length.unwrap_or_else(|| scene.tracks.first().map_or(0, |t| t.play.len())),
+        tracks,
+        tempo: scene.tempo,
+        ramp: scene.ramp.as_ref().map(|r| (r.to, r.bars as u64 * TICKS_PER_MEASURE)),
+        follow: scene.follow.iter().flatten().cloned().collect(),
+        follow_order,
+        sysex: scene
+            .sysex
+            .iter()
+            .flatten()
+            .filter_map(|s| Some((s.device.as_ref()?.to_string(), s.message(0).ok()?)))
+            .collect(),
+    }
+}
+
+/// Orders the followers so that each one comes after its leader, keeping the order of the scene
+/// otherwise. Followers left over are in a circle, they lose their leader and never advance.
+fn order_followers(tracks: &mut [TrackPlan]) -> Vec<usize> {
+    let mut ordered = tracks.iter().map(|t| !t.follower).collect::<Vec<_>>();
+    let mut order: Vec<usize> = Vec::new();
+    loop {
+        let count = order.len();
+        for (index, track) in tracks.iter().enumerate() {
+            if !ordered[index] && track.leader.is_none_or(|leader| ordered[leader]) {
+                ordered[index] = true;
+                order.push(index);
+            }
+        }
+        if order.len() == count {
+            break;
+        }
+    }
+    for index in (0..tracks.len()).filter(|i| !ordered[*i]) {
+        tracks[index].leader = None;
+        order.push(index);
+    }
+    order
+}
+
+fn compile_instrument(instrument: &Instrument) -> InstrumentPlan {
+    let data = instrument
+        .data
+        .iter()
+        .flatten()
+        .map(|d| DataLane {
+            device: d.device.to_string(),
+            channel: d.channel - 1,
+            control: d.control,
+        })
+        .collect::<Vec<_>>();
+    let channel = instrument.channel - 1;
+    let sequences = instrument.sequences.iter().map(|s| compile_sequence(s, channel, data.len())).collect();
+
+    InstrumentPlan {
+        name: instrument.name.to_string(),
+        device: instrument.device.to_string(),
+        channel,
+        data,
+        sequences,
+    }
+}
+
+fn compile_sequence(sequence: &Sequence, channel: u8, lanes: usize) -> SequencePlan {
+    let total_steps = sequence.steps.len() * 2;
+    SequencePlan {
+        name: sequence.name.to_string(),
+        ticks_per_step: TICKS_PER_MEASURE as usize / total_steps.max(1),
+        steps: sequence
+            .steps
+            .iter()
+            .map(|step| step.as_ref().map(|s| compile_step(s, channel, lanes)))
+            .collect(),
+    }
+}
+
+fn compile_step(step: &SequenceStep, channel: u8, lanes: usize) -> StepPlan {
+    let mut controls: Vec<MidiMessage> = Vec::new();
+    if let Some(bend) = step.bend {
+        controls.push(pitch_bend(channel, (bend as i32 + 8192) as u16));
+    }
+    for parameter in step.rpn.iter().flatten() {
+        controls.extend(&rpn(channel, parameter.number, parameter.value));
+    }
+    for parameter in step.nrpn.iter().flatten() {
+        controls.extend(&nrpn(channel, parameter.number, parameter.value));
+    }
+    for parameter in step.cc14.iter().flatten() {
+        controls.extend(&control_change_14bit(channel, parameter.number as u8, parameter.value));
+    }
+
+    StepPlan {
+        notes: step.pitch.iter().flatten().map(parse_midi_note).collect(),
+        velocity: step.velocity.as_ref().map_or(DEFAULT_VELOCITY, parse_midi_note),
+        program: step.program,
+        controls,
+        aftertouch: step.aftertouch,
+        poly_aftertouch: step.poly_aftertouch,
+        sysex: step
+            .sysex
+            .iter()
+            .flatten()
+            .filter_map(|s| Some((s.device.clone(), s.message(channel).ok()?)))
+            .collect(),
+        fill: step.fill,
+        data: step.data.as_ref().map(|values| values.iter().take(lanes).cloned().collect()),
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::{InstrumentBuilder, PerformanceBuilder, SceneBuilder, SequenceBuilder, StepBuilder};
+    use crate::config::TICKS_PER_MEASURE;
+    use crate::models::{Performance, PlaylistEntry, Track, TrackFollow};
+    use crate::plan::PerformancePlan;
+
+    fn track(instrument: &str, follow: Option<&str>, play: &[&str]) -> Track {
+        Track {
+            instrument: instrument.to_string(),
+            follow: follow.map(|f| TrackFollow::Instrument(f.to_string())),
+            play: play.iter().map(|p| p.to_string()).collect(),
+            fill: None,
+        }
+    }
+
+    fn performance() -> Performance {
+        let seq = |name| {
+            SequenceBuilder::new(name)
+                .step(StepBuilder::new().pitch("C5").pitch("64").velocity(90).data(vec![1, 2, 3]).build())
+                .rest()
+                .build()
+                .unwrap()
+        };
+        let drums = InstrumentBuilder::new("drums", "synth", 10).sequence(seq("A")).sequence(seq("B"));
+        let bass = InstrumentBuilder::new("bass", "synth", 2).data("fx", 3, 74).sequence(seq("A"));
+
+        PerformanceBuilder::new()
+            .play("verse")
+            .play("missing")
+            .scene(
+                SceneBuilder::new("verse")
+                    .track(track("drums", None, &["A", "B"]))
+                    .track(track("ghost", None, &["A"]))
+                    .track(track("bass", Some("drums"), &["A", "X"]))
+                    .build()
+                    .unwrap(),
+            )
+            .instrument(drums.build().unwrap())
+            .instrument(bass.build().unwrap())
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compile() {
+        let plan = PerformancePlan::compile(&performance());
+        assert_eq!(plan.playlist.iter().map(|s| s.scene).collect::<Vec<_>>(), vec![Some(0), None]);
+        assert!(plan.scene_at(1).is_none());
+        assert_eq!(plan.scene_at(2).unwrap().name, "verse");
+
+        let scene = &plan.scenes[0];
+        assert_eq!(scene.length, 2);
+        assert_eq!(scene.tracks.len(), 2);
+        assert_eq!((scene.tracks[0].instrument, scene.tracks[0].follower), (0, false));
+        assert_eq!(scene.tracks[0].play, vec![Some(0), Some(1)]);
+        assert_eq!((scene.tracks[1].instrument, scene.tracks[1].leader), (1, Some(0)));
+        assert_eq!(scene.tracks[1].play, vec![Some(0), None]);
+
+        let bass = &plan.instruments[1];
+        assert_eq!((bass.channel, bass.data[0].channel), (1, 2));
+        assert_eq!(bass.sequences[0].ticks_per_step, TICKS_PER_MEASURE as usize / 4);
+        let step = bass.sequences[0].steps[0].as_ref().unwrap();
+        assert_eq!((step.notes.to_vec(), step.velocity), (vec![60, 64], 90));
+        assert_eq!(step.data, Some(vec![1]));
+        assert!(bass.sequences[0].steps[1].is_none());
+    }
+
+    #[test]
+    fn test_compile_arrangement() {
+        let mut perf = performance();
+        perf.scenes[0].length = Some(4);
+        perf.playlist = vec![
+            PlaylistEntry::scene("verse").repeat(2).bars(1),
+            PlaylistEntry::section("chorus").repeat(2),
+            PlaylistEntry::scene("verse"),
+        ];
+        perf.sections = Some(vec![crate::models::Section {
+            name: String::from("chorus"),
+            playlist: vec![PlaylistEntry::scene("missing"), PlaylistEntry::scene("verse").bars(2)],
+        }]);
+        assert_eq!(perf.validate(), Ok(()));
+
+        let plan = PerformancePlan::compile(&perf);
+        let slots = plan.playlist.iter().map(|s| (s.name.as_str(), s.length)).collect::<Vec<_>>();
+        assert_eq!(
+            slots,
+            vec![("verse", 1), ("verse", 1), ("missing", 0), ("verse", 2), ("missing", 0), ("verse", 2), ("verse", 4)]
+        );
+        assert_eq!(plan.playlist[3].section, Some(String::from("chorus")));
+        assert_eq!(plan.find_slot("chorus"), Some(2));
+        assert_eq!(plan.find_slot("verse"), Some(0));
+
+        perf.playlist.push(PlaylistEntry::section("bridge"));
+        assert_eq!(perf.validate().unwrap_err(), "playlist plays unknown section \"bridge\"");
+    }
+
+    #[test]
+    fn test_follow_order() {
+        let mut perf = performance();
+        perf.scenes[0].tracks = vec![
+            track("bass", Some("drums"), &["A"]),
+            track("drums", Some("bass"), &["A"]),
+            track("drums", None, &["A"]),
+        ];
+        let plan = PerformancePlan::compile(&perf);
+        let leaders = plan.scenes[0].tracks.iter().map(|t| t.leader).collect::<Vec<_>>();
+        assert_eq!(leaders, vec![Some(2), Some(0), None]);
+        assert_eq!(plan.scenes[0].follow_order, vec![0, 1]);
+
+        perf.scenes[0].tracks.pop();
+        let plan = PerformancePlan::compile(&perf);
+        let leaders = plan.scenes[0].tracks.iter().map(|t| t.leader).collect::<Vec<_>>();
+        assert_eq!(leaders, vec![None, None]);
+    }
+
+    #[test]
+    fn test_compile_controls() {
+        let step = StepBuilder::new().pitch("C5").bend(-8192).rpn(0, 2 << 7).cc14(1, 16383).aftertouch(30).build();
+        let sequence = SequenceBuilder::new("A").step(step).build().unwrap();
+        let mut perf = performance();
+        perf.instruments = vec![InstrumentBuilder::new("lead", "synth", 3).sequence(sequence).build().unwrap()];
+
+        let plan = PerformancePlan::compile(&perf);
+        let step = plan.instruments[0].sequences[0].steps[0].as_ref().unwrap();
+        let controls = step.controls.iter().map(|m| (m.status, m.data1, m.data2)).collect::<Vec<_>>();
+        assert_eq!(
+            controls,
+            vec![
+                (0xE2, 0, 0),
+                (0xB2, 101, 0),
+                (0xB2, 100, 0),
+                (0xB2, 6, 2),
+                (0xB2, 38, 0),
+                (0xB2, 1, 127),
+                (0xB2, 33, 127),
+            ]
+        );
+        assert_eq!(step.aftertouch, Some(30));
+    }
+}
@@ -0,0 +1,90 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Random ------------------------------------------------------------------------------------------
+
+/// A small xorshift generator for picking follow actions. It is seeded explicitly, so that a
+/// performance rendered with the same seed always plays the same way.
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        // The state must never be zero
+        Random { state: (seed ^ 0x9E37_79B9_7F4A_7C15) | 1 }
+    }
+
+    /// Seeds the generator from the system clock.
+    pub fn from_time() -> Random {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Random::new(now.as_nanos() as u64)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number in `0..count`, or 0 if count is 0.
+    pub fn below(&mut self, count: usize) -> usize {
+        if count == 0 {
+            return 0;
+        }
+        (self.next_u64() % count as u64) as usize
+    }
+
+    /// The index of one of the weights, picked with a probability proportional to its weight.
+    pub fn weighted(&mut self, weights: impl Iterator<Item = u32> + Clone) -> usize {
+        let total = weights.clone().fold(0u64, |total, w| total.saturating_add(w as u64));
+        let mut pick = self.next_u64() % total.max(1);
+        for (index, weight) in weights.enumerate() {
+            if pick < weight as u64 {
+                return index;
+            }
+            pick -= weight as u64;
+        }
+        0
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::random::Random;
+
+    #[test]
+    fn test_weighted() {
+        let mut random = Random::new(7);
+        let weights = [1, 0, 3];
+        let mut counts = [0; 3];
+        for _ in 0..4000 {
+            counts[random.weighted(weights.iter().cloned())] += 1;
+        }
+        assert_eq!(counts[1], 0);
+        assert!(counts[0] > 800 && counts[0] < 1200, "counts {:?}", counts);
+        assert_eq!(Random::new(7).next_u64(), Random::new(7).next_u64());
+        assert!((0..100).all(|_| random.below(5) < 5));
+        assert!(random.weighted([u32::MAX, u32::MAX].iter().cloned()) < 2);
+    }
+}
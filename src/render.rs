@@ -0,0 +1,222 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::ffi::OsStr;
+use std::fs;
+
+use crate::config::{TICKS_PER_BEAT, TICKS_PER_MEASURE};
+use crate::engine::Engine;
+use crate::log;
+use crate::midi::{is_channel_message, parse_channel, parse_status, RecordedEvent};
+use crate::models::Performance;
+use crate::performance_file::load_performance_file;
+use crate::smf::{sysex_event, write_smf, SmfTrack};
+
+// Render ------------------------------------------------------------------------------------------
+
+/// The recorded messages and the tempo changes of a rendered performance.
+type Rendering = (Vec<RecordedEvent>, Vec<(u64, f64)>);
+
+/// Plays `bars` bars of the performance on a simulated clock and returns every message it sent,
+/// stamped with the tick it was sent on, and the ticks the tempo changed on. Notes still held at
+/// the end are released on the last tick. Fails if the performance doesn't validate.
+pub fn render_performance(perf: Performance, bars: u64) -> Result<Rendering, String> {
+    let mut engine = Engine::new(perf)?;
+
+    let mut events = engine.advance(bars * TICKS_PER_MEASURE);
+    events.extend(engine.stop());
+    Ok((events, engine.take_tempo_changes()))
+}
+
+/// Splits the events into one track per device and channel, and one per device for SysEx.
+pub fn events_to_tracks(events: &[RecordedEvent]) -> Vec<SmfTrack> {
+    let mut tracks: Vec<SmfTrack> = Vec::new();
+
+    for event in events.iter().filter(|e| e.sysex.is_some() || is_channel_message(e.message.status)) {
+        let message = event.message;
+        let name = match event.sysex {
+            Some(_) => format!("{} sysex", event.device),
+            None => format!("{} ch{}", event.device, parse_channel(message.status) + 1),
+        };
+
+        let index = match tracks.iter().position(|t| t.name == name) {
+            Some(index) => index,
+            None => {
+                tracks.push(SmfTrack::new(&name));
+                tracks.len() - 1
+            }
+        };
+
+        let bytes = match (&event.sysex, parse_status(message.status)) {
+            (Some(sysex), _) => sysex_event(sysex),
+            (None, 0xC0) | (None, 0xD0) => vec![message.status, message.data1],
+            _ => vec![message.status, message.data1, message.data2],
+        };
+        tracks[index].events.push((event.tick, bytes));
+    }
+
+    tracks
+}
+
+pub fn render_file(perf_file: &String, midi_file: &String, bars: u64, bpm: f64) -> Result<(), std::io::Error> {
+    let perf = load_performance_file(OsStr::new(perf_file))?;
+    // Performances on an external clock usually set no tempo, they are written at `bpm`
+    let sets_tempo =
+        perf.controller.tempo.is_some() || perf.scenes.iter().any(|s| s.tempo.is_some() || s.ramp.is_some());
+
+    let (events, tempos) =
+        render_performance(perf, bars).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let tracks = events_to_tracks(&events);
+    let tempos = if sets_tempo { tempos } else { vec![(0, bpm)] };
+    fs::write(midi_file, write_smf(TICKS_PER_BEAT as u16, &tempos, &tracks))?;
+
+    log::success(
+        format!("RENDERED {} bar(s), {} event(s) in {} track(s) to {:?}", bars, events.len(), tracks.len(), midi_file),
+        0,
+    );
+    Ok(())
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+/// Golden tests render the performances in `data/` and compare every message against the dumps in
+/// `data/golden/`. Run with `UPDATE_GOLDEN=1` to rewrite the dumps after an intended change.
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::config::TICKS_PER_MEASURE;
+    use crate::engine::Engine;
+    use crate::midi::RecordedEvent;
+    use crate::models::{ControlAction, Performance, Quantize};
+    use crate::performance_file::load_performance_file;
+    use crate::render::render_performance;
+
+    const GOLDEN_BARS: u64 = 24;
+
+    fn data_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data")
+    }
+
+    fn load(name: &str) -> Performance {
+        load_performance_file(data_dir().join(name).as_os_str()).unwrap()
+    }
+
+    fn format_tempos(tempos: &[(u64, f64)]) -> String {
+        tempos.iter().map(|(tick, bpm)| format!("{}\ttempo\t{:.3}\n", tick, bpm)).collect()
+    }
+
+    fn format_events(events: &[RecordedEvent]) -> String {
+        events
+            .iter()
+            .map(|e| {
+                let m = e.message;
+                let bytes = match &e.sysex {
+                    Some(sysex) => sysex.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" "),
+                    None => format!("{:02X} {:02X} {:02X}", m.status, m.data1, m.data2),
+                };
+                format!("{}\t{}\t{}\n", e.tick, e.device, bytes)
+            })
+            .collect()
+    }
+
+    fn assert_golden(name: &str, actual: String) {
+        let path = data_dir().join("golden").join(format!("{}.txt", name));
+
+        if env::var("UPDATE_GOLDEN").is_ok() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &actual).unwrap();
+            return;
+        }
+
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Missing golden file {:?} ({}), run with UPDATE_GOLDEN=1", path, e));
+        let mismatch = expected.lines().zip(actual.lines()).enumerate().find(|(_, (e, a))| e != a);
+        if let Some((index, (e, a))) = mismatch {
+            panic!("{} differs at line {}\nexpected: {}\n  actual: {}", name, index + 1, e, a);
+        }
+        assert_eq!(expected.lines().count(), actual.lines().count(), "{} has a different length", name);
+    }
+
+    #[test]
+    fn test_golden_data_files() {
+        let mut paths = fs::read_dir(data_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some(OsStr::new("yaml")))
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert!(!paths.is_empty());
+
+        for path in paths {
+            let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+            let perf = load_performance_file(path.as_os_str()).unwrap();
+            let (events, tempos) = render_performance(perf, GOLDEN_BARS).unwrap();
+            assert_golden(&name, format_tempos(&tempos) + &format_events(&events));
+        }
+    }
+
+    #[test]
+    fn test_golden_reload() {
+        let perf = load("mobile.yaml");
+        let mut engine = Engine::new(perf.clone()).unwrap();
+        let mut events = engine.advance(2 * TICKS_PER_MEASURE + 100);
+
+        // Staged in the middle of bar 3 and applied at the start of bar 4
+        let mut def = perf.clone();
+        def.controller.reload = Some(Quantize::Bar);
+        def.scenes[1].tracks[0].play = vec![String::from("B"); 4];
+        events.extend(engine.update(def));
+
+        events.extend(engine.advance(10 * TICKS_PER_MEASURE - 100));
+        events.extend(engine.stop());
+        assert_golden("mobile-reload", format_events(&events));
+    }
+
+    #[test]
+    fn test_golden_actions() {
+        let mut engine = Engine::new(load("mobile.yaml")).unwrap();
+        let mut events = engine.advance(2 * TICKS_PER_MEASURE + 100);
+
+        events.extend(engine.action(ControlAction::Jump(String::from("INTRO3")), Quantize::Bar));
+        events.extend(engine.action(ControlAction::Mute(String::from("poly1B")), Quantize::Beat));
+        events.extend(engine.advance(3 * TICKS_PER_MEASURE));
+
+        events.extend(engine.action(ControlAction::Next, Quantize::Immediate));
+        events.extend(engine.advance(3 * TICKS_PER_MEASURE - 100));
+        events.extend(engine.stop());
+        assert_golden("mobile-actions", format_events(&events));
+    }
+
+    #[test]
+    fn test_golden_fill() {
+        let mut engine = Engine::new(load("fill.yaml")).unwrap();
+        let mut events = engine.advance(TICKS_PER_MEASURE + 100);
+
+        events.extend(engine.action(ControlAction::Fill, Quantize::Bar));
+        events.extend(engine.advance(2 * TICKS_PER_MEASURE));
+
+        events.extend(engine.action(ControlAction::Fill, Quantize::Immediate));
+        events.extend(engine.advance(2 * TICKS_PER_MEASURE - 100));
+        events.extend(engine.stop());
+        assert_golden("fill-actions", format_events(&events));
+    }
+}
@@ -0,0 +1,207 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+extern crate portmidi;
+
+use portmidi::{MidiEvent, MidiMessage};
+use std::thread;
+
+use crossbeam_channel::{select, unbounded, Receiver, Sender};
+
+use crate::midi::{is_channel_message, parse_channel, parse_midi_note, parse_status, DeviceManager, MidiOutput};
+use crate::models::{Route, RouteTarget, VelocityCurve};
+
+// Router ------------------------------------------------------------------------------------------
+
+/// Starts a thread that routes the events sent to it, so that the writes of MIDI thru don't delay
+/// the clock. The routes are replaced whenever a new list arrives on `routes_recv`.
+pub fn start_router(routes: Vec<Route>, routes_recv: Receiver<Vec<Route>>) -> Sender<(String, Vec<MidiEvent>)> {
+    let (events_send, events_recv) = unbounded::<(String, Vec<MidiEvent>)>();
+    thread::spawn(move || {
+        let mut router = Router::new(routes);
+        loop {
+            select! {
+                recv(routes_recv) -> msg => router.update_def(msg.expect("routes channel closed")),
+                recv(events_recv) -> msg => {
+                    let (device_name, events) = msg.expect("router channel closed");
+                    router.route(&device_name, &events);
+                }
+            }
+        }
+    });
+    events_send
+}
+
+/// Forwards incoming channel messages to the outputs of all matching `routes`.
+pub struct Router {
+    routes: Vec<Route>,
+    device_manager: DeviceManager,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>) -> Router {
+        Router {
+            routes,
+            device_manager: DeviceManager::new(),
+        }
+    }
+
+    pub fn update_def(&mut self, routes: Vec<Route>) {
+        self.routes = routes;
+    }
+
+    pub fn route(&mut self, device_name: &str, events: &[MidiEvent]) {
+        for route in self.routes.iter().filter(|r| r.device == device_name) {
+            for target in &route.to {
+                let messages = events
+                    .iter()
+                    .filter_map(|e| transform(route, target, e.message))
+                    .collect::<Vec<_>>();
+                if !messages.is_empty() {
+                    self.device_manager.write_messages(&target.device, &messages);
+                }
+            }
+        }
+    }
+}
+
+// Transforms --------------------------------------------------------------------------------------
+
+/// Applies a route's filters and transforms to a single message on its way to `target`. Returns
+/// `None` if the message is not routed, e.g. because it is outside of the note range.
+pub fn transform(route: &Route, target: &RouteTarget, message: MidiMessage) -> Option<MidiMessage> {
+    if !is_channel_message(message.status) {
+        return None;
+    }
+
+    let in_channel = parse_channel(message.status);
+    if route.channel.is_some_and(|c| c - 1 != in_channel) {
+        return None;
+    }
+
+    let status = parse_status(message.status);
+    let mut data1 = message.data1;
+    let mut data2 = message.data2;
+
+    // Note off, note on and poly aftertouch carry a pitch
+    if status == 0x80 || status == 0x90 || status == 0xA0 {
+        if let Some(range) = &route.range {
+            let low = range.first().map_or(0, parse_midi_note);
+            let high = range.get(1).map_or(127, parse_midi_note);
+            if !(low..=high).contains(&data1) {
+                return None;
+            }
+        }
+
+        let pitch = data1 as i16 + route.transpose.unwrap_or(0) as i16;
+        if !(0..=127).contains(&pitch) {
+            return None;
+        }
+        data1 = pitch as u8;
+
+        if status == 0x90 && data2 > 0 {
+            data2 = apply_velocity_curve(route.velocity.unwrap_or(VelocityCurve::Linear), data2);
+        }
+    }
+
+    let out_channel = target.channel.map_or(in_channel, |c| c - 1);
+
+    Some(MidiMessage {
+        status: status + out_channel,
+        data1,
+        data2,
+        data3: 0,
+    })
+}
+
+pub fn apply_velocity_curve(curve: VelocityCurve, velocity: u8) -> u8 {
+    let v = velocity as f64 / 127.0;
+    let result = match curve {
+        VelocityCurve::Linear => return velocity,
+        VelocityCurve::Fixed(value) => return value.clamp(1, 127),
+        VelocityCurve::Soft => v.sqrt(),
+        VelocityCurve::Hard => v * v,
+    };
+    // Never turn a note-on into a note-off
+    ((result * 127.0).round() as u8).clamp(1, 127)
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use portmidi::MidiMessage;
+
+    use crate::midi::{control_change, note_on};
+    use crate::models::{Route, RouteTarget, VelocityCurve};
+    use crate::router::{apply_velocity_curve, transform};
+
+    fn route() -> Route {
+        Route {
+            device: String::from("keys"),
+            channel: Some(1),
+            to: vec![],
+            transpose: None,
+            velocity: None,
+            range: None,
+        }
+    }
+
+    fn target(channel: Option<u8>) -> RouteTarget {
+        RouteTarget {
+            device: String::from("synth"),
+            channel,
+        }
+    }
+
+    #[test]
+    fn test_transform_channel() {
+        let r = route();
+        assert_eq!(transform(&r, &target(Some(5)), note_on(0, 60, 100)), Some(note_on(4, 60, 100)));
+        assert_eq!(transform(&r, &target(None), note_on(0, 60, 100)), Some(note_on(0, 60, 100)));
+        assert_eq!(transform(&r, &target(None), note_on(1, 60, 100)), None);
+        assert_eq!(transform(&r, &target(Some(2)), control_change(0, 7, 90)), Some(control_change(1, 7, 90)));
+
+        let clock = MidiMessage { status: 0xF8, data1: 0, data2: 0, data3: 0 };
+        assert_eq!(transform(&r, &target(None), clock), None);
+    }
+
+    #[test]
+    fn test_transform_notes() {
+        let mut r = route();
+        r.transpose = Some(12);
+        r.range = Some(vec![String::from("C5"), String::from("B5")]);
+        assert_eq!(transform(&r, &target(None), note_on(0, 60, 100)), Some(note_on(0, 72, 100)));
+        assert_eq!(transform(&r, &target(None), note_on(0, 59, 100)), None);
+        assert_eq!(transform(&r, &target(None), note_on(0, 72, 100)), None);
+
+        r.range = None;
+        r.transpose = Some(-61);
+        assert_eq!(transform(&r, &target(None), note_on(0, 60, 100)), None);
+    }
+
+    #[test]
+    fn test_apply_velocity_curve() {
+        assert_eq!(apply_velocity_curve(VelocityCurve::Linear, 64), 64);
+        assert_eq!(apply_velocity_curve(VelocityCurve::Fixed(100), 12), 100);
+        assert_eq!(apply_velocity_curve(VelocityCurve::Soft, 32), 64);
+        assert_eq!(apply_velocity_curve(VelocityCurve::Hard, 64), 32);
+        assert_eq!(apply_velocity_curve(VelocityCurve::Hard, 1), 1);
+        assert_eq!(apply_velocity_curve(VelocityCurve::Soft, 127), 127);
+    }
+}
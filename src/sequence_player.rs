@@ -20,124 +20,141 @@ extern crate portmidi;
 
 use portmidi::MidiMessage;
 
-use crate::config::{DEFAULT_VELOCITY, TICKS_PER_MEASURE};
 use crate::midi;
-use crate::midi::{DeviceManager, parse_midi_note};
-use crate::models::{Instrument};
+use crate::midi::MidiOutput;
+use crate::plan::InstrumentPlan;
 
 // Sequence Player ---------------------------------------------------------------------------------
 
+/// Plays one sequence of an instrument at a time. The instrument is passed in on every call, so the
+/// player only holds its position and the notes it has to release.
 pub struct SequencePlayer {
-    pub instrument: Instrument,
-    pub seq_name: String,
+    pub sequence: Option<usize>,
     step_index: usize,
     clock_count: usize,
     pub bar_count: usize,
     note_on_list: Vec<u8>,
+    messages: Vec<MidiMessage>,
+    muted: bool,
 }
 
 impl SequencePlayer {
-    pub fn new(inst: Instrument, seq_name: String) -> SequencePlayer {
+    pub fn new(sequence: Option<usize>) -> SequencePlayer {
         SequencePlayer {
-            instrument: inst,
-            seq_name: seq_name,
+            sequence,
             step_index: 0,
             clock_count: 0,
             bar_count: 0,
             note_on_list: Vec::new(),
+            messages: Vec::new(),
+            muted: false,
+        }
+    }
+
+    /// A muted player keeps its position and still reports triggered notes to its followers, but
+    /// doesn't send any messages. Notes that are still held are released when it gets muted.
+    pub fn set_muted(&mut self, muted: bool, instrument: &InstrumentPlan, device_manager: &mut dyn MidiOutput) {
+        if muted && !self.muted {
+            self.note_off_all(instrument, device_manager);
+        }
+        self.muted = muted;
+    }
+
+    /// Starts over with another sequence as if the player was new, but keeps its buffers.
+    pub fn restart(&mut self, sequence: Option<usize>) {
+        self.sequence = sequence;
+        self.reset();
+        self.note_on_list.clear();
+        self.muted = false;
+    }
+
+    /// Switches to another sequence at the same position in the bar. The step is recomputed from
+    /// the clock, since sequences with a different number of steps have different step lengths.
+    pub fn switch_sequence(&mut self, sequence: Option<usize>, instrument: &InstrumentPlan) {
+        self.sequence = sequence;
+        if let Some(sequence) = sequence.map(|index| &instrument.sequences[index]) {
+            // Steps already passed, the one due at the current clock plays next
+            self.step_index = self.clock_count.div_ceil(sequence.ticks_per_step);
         }
     }
 
-    #[allow(dead_code)]
     pub fn reset(&mut self) {
         self.step_index = 0;
         self.clock_count = 0;
         self.bar_count = 0;
     }
 
-    pub fn next_bar(&mut self, device_manager: &mut DeviceManager) {
+    pub fn next_bar(&mut self, instrument: &InstrumentPlan, device_manager: &mut dyn MidiOutput) {
         self.step_index = 0;
         self.clock_count = 0;
         self.bar_count += 1;
-        self.note_off_all(device_manager);
+        self.note_off_all(instrument, device_manager);
     }
 
-    pub fn note_off_all(&mut self, device_manager: &mut DeviceManager) {
-        let mut messages: Vec<MidiMessage> = Vec::new();
-        for note in &self.note_on_list {
-            messages.push(midi::note_off(self.instrument.channel - 1, *note, 0));
-        }
-        if messages.len() > 0 {
-            device_manager.write_messages(self.instrument.device.to_string(), messages);
+    pub fn note_off_all(&mut self, instrument: &InstrumentPlan, device_manager: &mut dyn MidiOutput) {
+        if !self.note_on_list.is_empty() {
+            self.messages.clear();
+            for note in &self.note_on_list {
+                self.messages.push(midi::note_off(instrument.channel, *note, 0));
+            }
+            device_manager.write_messages(&instrument.device, &self.messages);
         }
         self.note_on_list.clear();
     }
 
-    pub fn clock(&mut self, device_manager: &mut DeviceManager) -> bool {
+    /// Plays the current tick and returns the sequence and step whose notes it triggered. Steps that
+    /// are conditional on fills only play if `fill` matches.
+    pub fn clock(
+        &mut self,
+        instrument: &InstrumentPlan,
+        device_manager: &mut dyn MidiOutput,
+        fill: bool,
+    ) -> Option<(usize, usize)> {
         // double the step length, so that we can note-off on odd steps
-        let mut messages: Vec<MidiMessage> = Vec::new();
-
-        let mut note_on_was_triggered = false;
-
-        let seq_name = self.seq_name.to_owned();
-        let instrument = &self.instrument;
-        let note_on_list = &mut self.note_on_list;
-        let inst_channel = instrument.channel - 1;
+        self.messages.clear();
 
+        let mut triggered: Option<(usize, usize)> = None;
         let mut note_off_all = false;
 
-        for sequence in instrument.sequences.iter().filter(|s| s.name == seq_name) {
+        if let Some((index, sequence)) = self.sequence.map(|index| (index, &instrument.sequences[index])) {
             let total_steps = sequence.steps.len() * 2;
-            let ticks_per_step = TICKS_PER_MEASURE as usize / total_steps;
-
-            if self.clock_count % ticks_per_step == 0 && self.step_index < total_steps {
-                if self.step_index % 2 == 0 {
-                    let maybe_step = &sequence.steps[self.step_index / 2];
-                    maybe_step.as_ref().map(|step| {
-                        let mut velocity = DEFAULT_VELOCITY;
-                        step.velocity.as_ref().map(|value| velocity = parse_midi_note(&value));
-
-                        step.pitch.as_ref().map(|notes| {
-                            for note in notes {
-                                let p = parse_midi_note(note);
-                                messages.push(midi::note_on(
-                                    inst_channel,
-                                    p,
-                                    velocity,
-                                ));
-                                note_on_was_triggered = true;
-                                note_on_list.push(p);
+
+            if self.clock_count.is_multiple_of(sequence.ticks_per_step) && self.step_index < total_steps {
+                if self.step_index.is_multiple_of(2) {
+                    let step = sequence.steps[self.step_index / 2].as_ref();
+                    if let Some(step) = step.filter(|s| s.fill.is_none_or(|f| f == fill)) {
+                        for (device, message) in step.sysex.iter().filter(|_| !self.muted) {
+                            device_manager.write_sysex(device.as_ref().unwrap_or(&instrument.device), message);
+                        }
+                        self.messages.extend(&step.controls);
+                        for note in &step.notes {
+                            self.messages.push(midi::note_on(instrument.channel, *note, step.velocity));
+                            triggered = Some((index, self.step_index / 2));
+                            if !self.muted {
+                                self.note_on_list.push(*note);
+                            }
+                        }
+
+                        if let Some(program) = step.program {
+                            self.messages.push(midi::program_change(instrument.channel, program));
+                        }
+
+                        if let Some(pressure) = step.poly_aftertouch {
+                            for note in &step.notes {
+                                self.messages.push(midi::poly_pressure(instrument.channel, *note, pressure));
                             }
-                        });
-
-                        step.program.as_ref().map(|p| {
-                            messages.push(midi::program_change(
-                                inst_channel,
-                                *p
-                            ));
-                        });
-
-                        step.data.as_ref().map(|values| {
-                            for i in 0..values.len() {
-                                let value = values[i];
-
-                                instrument.data.as_ref().map(|mod_devices| {
-                                    if i < mod_devices.len() {
-                                        let device = &mod_devices[i];
-                                        let message = midi::control_change(
-                                            device.channel.to_owned() - 1,
-                                            device.control,
-                                            value,
-                                        );
-                                        device_manager.write_messages(
-                                            device.device.to_string(),
-                                            vec![message],
-                                        );
-                                    }
-                                });
+                        }
+                        if let Some(pressure) = step.aftertouch {
+                            self.messages.push(midi::channel_pressure(instrument.channel, pressure));
+                        }
+
+                        if let Some(values) = step.data.as_ref().filter(|_| !self.muted) {
+                            for (lane, value) in instrument.data.iter().zip(values) {
+                                let message = midi::control_change(lane.channel, lane.control, *value);
+                                device_manager.write_messages(&lane.device, &[message]);
                             }
-                        });
-                    });
+                        }
+                    }
                 } else {
                     note_off_all = true;
                 }
@@ -148,13 +165,15 @@ impl SequencePlayer {
         self.clock_count += 1;
 
         if note_off_all {
-            self.note_off_all(device_manager);
+            // Odd steps never add any messages, so the buffer is free for the note-offs
+            self.note_off_all(instrument, device_manager);
+            self.messages.clear();
         }
 
-        if messages.len() > 0 {
-            device_manager.write_messages(self.instrument.device.to_string(), messages);
+        if !self.messages.is_empty() && !self.muted {
+            device_manager.write_messages(&instrument.device, &self.messages);
         }
 
-        note_on_was_triggered
+        triggered
     }
 }
@@ -0,0 +1,311 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+// Standard MIDI File ------------------------------------------------------------------------------
+
+/// A track of a Standard MIDI File. Events are raw message bytes at an absolute tick and have to be
+/// in chronological order.
+pub struct SmfTrack {
+    pub name: String,
+    pub events: Vec<(u64, Vec<u8>)>,
+}
+
+impl SmfTrack {
+    pub fn new(name: &str) -> SmfTrack {
+        SmfTrack {
+            name: name.to_string(),
+            events: Vec::new(),
+        }
+    }
+}
+
+/// Encodes a type 1 Standard MIDI File. The first track only carries a 4/4 time signature and the
+/// tempo changes, as ticks and BPM in chronological order, followed by one chunk for each of
+/// `tracks`.
+pub fn write_smf(ticks_per_quarter: u16, tempos: &[(u64, f64)], tracks: &[SmfTrack]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    bytes.extend_from_slice(b"MThd");
+    bytes.extend_from_slice(&6u32.to_be_bytes());
+    bytes.extend_from_slice(&1u16.to_be_bytes());
+    bytes.extend_from_slice(&(tracks.len() as u16 + 1).to_be_bytes());
+    bytes.extend_from_slice(&ticks_per_quarter.to_be_bytes());
+
+    let mut tempo_track = SmfTrack::new("tempo");
+    tempo_track.events.push((0, vec![0xFF, 0x58, 0x04, 0x04, 0x02, 0x18, 0x08]));
+    for (tick, bpm) in tempos {
+        let micros_per_quarter = (60_000_000.0 / bpm).round() as u32;
+        let mut event = vec![0xFF, 0x51, 0x03];
+        event.extend_from_slice(&micros_per_quarter.to_be_bytes()[1..]);
+        tempo_track.events.push((*tick, event));
+    }
+    write_track(&mut bytes, &tempo_track);
+
+    for track in tracks {
+        write_track(&mut bytes, track);
+    }
+
+    bytes
+}
+
+fn write_track(bytes: &mut Vec<u8>, track: &SmfTrack) {
+    let mut data: Vec<u8> = Vec::new();
+
+    data.extend_from_slice(&[0x00, 0xFF, 0x03]);
+    write_variable_length(&mut data, track.name.len() as u64);
+    data.extend_from_slice(track.name.as_bytes());
+
+    let mut last_tick = 0;
+    for (tick, event) in &track.events {
+        write_variable_length(&mut data, tick - last_tick);
+        data.extend_from_slice(event);
+        last_tick = *tick;
+    }
+    data.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+    bytes.extend_from_slice(b"MTrk");
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(&data);
+}
+
+/// Encodes a SysEx message from F0 to F7 as an event, with its length after the F0.
+pub fn sysex_event(message: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0xF0];
+    write_variable_length(&mut bytes, message.len().saturating_sub(1) as u64);
+    bytes.extend_from_slice(message.get(1..).unwrap_or(&[]));
+    bytes
+}
+
+pub fn write_variable_length(bytes: &mut Vec<u8>, value: u64) {
+    let mut buffer: Vec<u8> = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        buffer.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    buffer.reverse();
+    bytes.extend_from_slice(&buffer);
+}
+
+/// Decodes a Standard MIDI File into its ticks per quarter note and tracks. Only channel messages
+/// are kept, meta events other than the track name and all SysEx data are skipped.
+pub fn read_smf(bytes: &[u8]) -> Result<(u16, Vec<SmfTrack>), String> {
+    let mut pos = 0;
+    let mut ticks_per_quarter: Option<u16> = None;
+    let mut tracks: Vec<SmfTrack> = Vec::new();
+
+    while pos + 8 <= bytes.len() {
+        let chunk_type = &bytes[pos..pos + 4];
+        let length = u32::from_be_bytes([bytes[pos + 4], bytes[pos + 5], bytes[pos + 6], bytes[pos + 7]]) as usize;
+        let start = pos + 8;
+        let end = start + length;
+        if end > bytes.len() {
+            return Err(String::from("chunk exceeds the end of the file"));
+        }
+
+        if chunk_type == b"MThd" {
+            if length < 6 {
+                return Err(String::from("header chunk is too short"));
+            }
+            let division = u16::from_be_bytes([bytes[start + 4], bytes[start + 5]]);
+            if division & 0x8000 != 0 {
+                return Err(String::from("SMPTE time division is not supported"));
+            }
+            if division == 0 {
+                return Err(String::from("time division of 0 ticks per quarter note"));
+            }
+            ticks_per_quarter = Some(division);
+        } else if chunk_type == b"MTrk" {
+            let name = format!("track{}", tracks.len() + 1);
+            tracks.push(read_track(&bytes[start..end], &name)?);
+        }
+
+        pos = end;
+    }
+
+    match ticks_per_quarter {
+        Some(division) => Ok((division, tracks)),
+        None => Err(String::from("missing header chunk")),
+    }
+}
+
+fn read_track(bytes: &[u8], default_name: &str) -> Result<SmfTrack, String> {
+    let mut track = SmfTrack::new(default_name);
+    let mut has_name = false;
+    let mut pos = 0;
+    let mut tick = 0;
+    let mut running_status: u8 = 0;
+
+    while pos < bytes.len() {
+        tick += read_variable_length(bytes, &mut pos)?;
+        let mut status = *bytes.get(pos).ok_or("unexpected end of track")?;
+
+        match status {
+            0xFF => {
+                let meta_type = *bytes.get(pos + 1).ok_or("unexpected end of track")?;
+                pos += 2;
+                let length = read_variable_length(bytes, &mut pos)? as usize;
+                let data = bytes.get(pos..pos + length).ok_or("unexpected end of track")?;
+                if meta_type == 0x03 && !has_name {
+                    track.name = String::from_utf8_lossy(data).to_string();
+                    has_name = true;
+                } else if meta_type == 0x2F {
+                    break;
+                }
+                pos += length;
+                // Meta and SysEx events cancel the running status
+                running_status = 0;
+            }
+            0xF0 | 0xF7 => {
+                pos += 1;
+                pos += read_variable_length(bytes, &mut pos)? as usize;
+                running_status = 0;
+            }
+            _ => {
+                if status >= 0x80 {
+                    running_status = status;
+                    pos += 1;
+                } else if running_status >= 0x80 {
+                    status = running_status;
+                } else {
+                    return Err(String::from("data byte without running status"));
+                }
+
+                let data_length = match status & 0xF0 {
+                    0xC0 | 0xD0 => 1,
+                    _ => 2,
+                };
+                let data = bytes.get(pos..pos + data_length).ok_or("unexpected end of track")?;
+                let mut event = vec![status];
+                event.extend_from_slice(data);
+                track.events.push((tick, event));
+                pos += data_length;
+            }
+        }
+    }
+
+    Ok(track)
+}
+
+fn read_variable_length(bytes: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value: u64 = 0;
+    for _ in 0..4 {
+        let byte = *bytes.get(*pos).ok_or("unexpected end of track")?;
+        *pos += 1;
+        value = (value << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(String::from("variable length quantity is too long"))
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::smf::{read_smf, sysex_event, write_smf, write_variable_length, SmfTrack};
+
+    fn variable_length(value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_variable_length(&mut bytes, value);
+        bytes
+    }
+
+    #[test]
+    fn test_write_variable_length() {
+        assert_eq!(variable_length(0), vec![0x00]);
+        assert_eq!(variable_length(0x7F), vec![0x7F]);
+        assert_eq!(variable_length(0x80), vec![0x81, 0x00]);
+        assert_eq!(variable_length(0x2000), vec![0xC0, 0x00]);
+        assert_eq!(variable_length(0x0FFFFFFF), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn test_sysex_event() {
+        assert_eq!(sysex_event(&[0xF0, 0x7E, 0x7F, 0x09, 0x01, 0xF7]), vec![0xF0, 0x05, 0x7E, 0x7F, 0x09, 0x01, 0xF7]);
+    }
+
+    #[test]
+    fn test_write_smf() {
+        let mut track = SmfTrack::new("a");
+        track.events.push((0, vec![0x90, 60, 100]));
+        track.events.push((96, vec![0x80, 60, 0]));
+        let bytes = write_smf(96, &[(0, 120.0), (192, 150.0)], &[track]);
+
+        assert_eq!(&bytes[0..14], &[b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 2, 0, 96]);
+        // Tempo track with 500000us and then 400000us per quarter note
+        assert_eq!(&bytes[14..18], b"MTrk");
+        assert_eq!(&bytes[39..46], &[0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20]);
+        assert_eq!(&bytes[46..54], &[0x81, 0x40, 0xFF, 0x51, 0x03, 0x06, 0x1A, 0x80]);
+
+        let track_start = 22 + u32::from_be_bytes([bytes[18], bytes[19], bytes[20], bytes[21]]) as usize;
+        assert_eq!(&bytes[track_start..track_start + 4], b"MTrk");
+        assert_eq!(
+            &bytes[track_start + 8..],
+            &[0x00, 0xFF, 0x03, 0x01, b'a', 0x00, 0x90, 60, 100, 0x60, 0x80, 60, 0, 0x00, 0xFF, 0x2F, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_read_smf() {
+        let mut track = SmfTrack::new("bass");
+        track.events.push((0, vec![0x91, 36, 100]));
+        track.events.push((0, vec![0xC1, 5]));
+        track.events.push((480, vec![0x81, 36, 0]));
+        let bytes = write_smf(480, &[(0, 120.0)], &[track]);
+
+        let (ticks_per_quarter, tracks) = read_smf(&bytes).unwrap();
+        assert_eq!(ticks_per_quarter, 480);
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].name, "tempo");
+        assert_eq!(tracks[0].events.len(), 0);
+        assert_eq!(tracks[1].name, "bass");
+        assert_eq!(
+            tracks[1].events,
+            vec![(0, vec![0x91, 36, 100]), (0, vec![0xC1, 5]), (480, vec![0x81, 36, 0])]
+        );
+    }
+
+    #[test]
+    fn test_read_smf_running_status() {
+        let mut bytes: Vec<u8> = vec![b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96];
+        let track: Vec<u8> = vec![0x00, 0x90, 60, 100, 0x60, 60, 0, 0x00, 0xFF, 0x2F, 0x00];
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+
+        let (_, tracks) = read_smf(&bytes).unwrap();
+        assert_eq!(tracks[0].name, "track1");
+        assert_eq!(tracks[0].events, vec![(0, vec![0x90, 60, 100]), (96, vec![0x90, 60, 0])]);
+        assert!(read_smf(&bytes[0..24]).is_err());
+
+        let mut no_division = bytes.clone();
+        no_division[12..14].copy_from_slice(&[0, 0]);
+        assert_eq!(read_smf(&no_division).err(), Some(String::from("time division of 0 ticks per quarter note")));
+
+        // A data byte after a meta event has no status to run on
+        let mut bytes: Vec<u8> = vec![b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1, 0, 96];
+        let track: Vec<u8> = vec![0x00, 0x90, 60, 100, 0x00, 0xFF, 0x01, 0x01, b'x', 0x60, 60, 0];
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+        assert_eq!(read_smf(&bytes).err(), Some(String::from("data byte without running status")));
+    }
+}
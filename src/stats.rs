@@ -0,0 +1,361 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{select, tick, unbounded, Receiver, Sender};
+
+use crate::config::{STATS_REPORT_INTERVAL_MS, STATS_SEND_INTERVAL_MS};
+use crate::log;
+
+// Histogram ---------------------------------------------------------------------------------------
+
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const HISTOGRAM_BUCKETS: usize = SUB_BUCKETS * 30;
+
+/// A histogram of microsecond durations. Values below 16µs are counted exactly, larger ones in 16
+/// buckets per power of two, so percentiles are within about 6% of the real value. Recording a
+/// value doesn't allocate.
+pub struct Histogram {
+    buckets: [u64; HISTOGRAM_BUCKETS],
+    count: u64,
+    min: u64,
+    max: u64,
+    sum: f64,
+    sum_squares: f64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: [0; HISTOGRAM_BUCKETS],
+            count: 0,
+            min: u64::MAX,
+            max: 0,
+            sum: 0.0,
+            sum_squares: 0.0,
+        }
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        self.record_micros(duration.as_micros() as u64);
+    }
+
+    pub fn record_micros(&mut self, micros: u64) {
+        self.buckets[bucket_index(micros)] += 1;
+        self.count += 1;
+        self.min = self.min.min(micros);
+        self.max = self.max.max(micros);
+        self.sum += micros as f64;
+        self.sum_squares += micros as f64 * micros as f64;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.sum_squares += other.sum_squares;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum / self.count as f64
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        (self.sum_squares / self.count as f64 - mean * mean).max(0.0).sqrt()
+    }
+
+    /// The upper bound of the bucket holding the given percentile, capped at the largest value.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let rank = ((percentile / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let (low, width) = bucket_range(index);
+                return (low + width - 1).min(self.max).max(self.min);
+            }
+        }
+        self.max
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "p50 {}us p99 {}us max {}us",
+            self.percentile(50.0),
+            self.percentile(99.0),
+            self.max
+        )
+    }
+
+    /// One line per power of two that holds any values, with a bar relative to the fullest one.
+    pub fn chart(&self) -> Vec<String> {
+        let octaves = self
+            .buckets
+            .chunks(SUB_BUCKETS)
+            .enumerate()
+            .map(|(octave, chunk)| (bucket_range(octave * SUB_BUCKETS).0, chunk.iter().sum::<u64>()))
+            .collect::<Vec<_>>();
+        let largest = octaves.iter().map(|(_, count)| *count).max().unwrap_or(0).max(1);
+
+        octaves
+            .iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(low, count)| {
+                format!(
+                    "  >= {:>8}us {:>9} {}",
+                    low,
+                    count,
+                    "#".repeat((count * 40 / largest) as usize)
+                )
+            })
+            .collect()
+    }
+}
+
+fn bucket_index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS as u64 {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros();
+    let sub_bucket = (micros >> (exponent - SUB_BUCKET_BITS)) as usize & (SUB_BUCKETS - 1);
+    let index = (exponent - SUB_BUCKET_BITS + 1) as usize * SUB_BUCKETS + sub_bucket;
+    index.min(HISTOGRAM_BUCKETS - 1)
+}
+
+/// The lowest value and the width of a bucket.
+fn bucket_range(index: usize) -> (u64, u64) {
+    if index < SUB_BUCKETS {
+        return (index as u64, 1);
+    }
+    let shift = (index / SUB_BUCKETS - 1) as u32;
+    let sub_bucket = (index % SUB_BUCKETS) as u64;
+    ((SUB_BUCKETS as u64 + sub_bucket) << shift, 1 << shift)
+}
+
+// Timing Stats ------------------------------------------------------------------------------------
+
+/// Timing measurements of the sequencer threads. Each thread fills in the measures it can take and
+/// sends them to the reporter, which merges them.
+pub struct TimingStats {
+    /// Time between incoming MIDI clocks of the controller.
+    pub clock_interval: Histogram,
+    /// How late the clock multiplier sends its ticks.
+    pub tick_latency: Histogram,
+    /// How late MIDI messages are written compared to the tick they were played on. Messages
+    /// written ahead of their tick count as zero.
+    pub write_latency: Histogram,
+    /// Clocks that arrived before the multiplier sent all ticks of the previous one.
+    pub early_clocks: u64,
+    /// Ticks left over by those clocks, which were spread over the following clock.
+    pub carried_ticks: u64,
+    /// Clocks the tempo follower ignored because they were too far from their predicted time.
+    pub clock_outliers: u64,
+    /// Ticks that didn't match the position of the performance within its bar.
+    pub phase_errors: u64,
+}
+
+impl TimingStats {
+    pub fn new() -> TimingStats {
+        TimingStats {
+            clock_interval: Histogram::new(),
+            tick_latency: Histogram::new(),
+            write_latency: Histogram::new(),
+            early_clocks: 0,
+            carried_ticks: 0,
+            clock_outliers: 0,
+            phase_errors: 0,
+        }
+    }
+
+    pub fn merge(&mut self, other: &TimingStats) {
+        self.clock_interval.merge(&other.clock_interval);
+        self.tick_latency.merge(&other.tick_latency);
+        self.write_latency.merge(&other.write_latency);
+        self.early_clocks += other.early_clocks;
+        self.carried_ticks += other.carried_ticks;
+        self.clock_outliers += other.clock_outliers;
+        self.phase_errors += other.phase_errors;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clock_interval.count() == 0 && self.tick_latency.count() == 0 && self.write_latency.count() == 0
+    }
+
+    pub fn summary(&self) -> String {
+        let clock = format!(
+            "clock {:.0}us ±{:.0}us ({})",
+            self.clock_interval.mean(),
+            self.clock_interval.std_dev(),
+            self.clock_interval.summary()
+        );
+        format!(
+            "STATS {} | tick late {} | write late {} | early clocks {} ({} ticks) | outliers {} | phase errors {}",
+            clock,
+            self.tick_latency.summary(),
+            self.write_latency.summary(),
+            self.early_clocks,
+            self.carried_ticks,
+            self.clock_outliers,
+            self.phase_errors
+        )
+    }
+}
+
+// Stats Collector ---------------------------------------------------------------------------------
+
+/// Collects the stats of a single thread and passes them on to the reporter now and then, so that
+/// threads don't share any state. Without a reporter the stats are dropped.
+pub struct StatsCollector {
+    pub stats: TimingStats,
+    send: Option<Sender<TimingStats>>,
+    last_sent: Instant,
+}
+
+impl StatsCollector {
+    pub fn new(send: Option<Sender<TimingStats>>) -> StatsCollector {
+        StatsCollector {
+            stats: TimingStats::new(),
+            send,
+            last_sent: Instant::now(),
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.send.is_some() && self.last_sent.elapsed() >= Duration::from_millis(STATS_SEND_INTERVAL_MS)
+    }
+
+    /// Sends the stats collected so far if they are due, and starts over.
+    pub fn send_due(&mut self) {
+        if !self.is_due() {
+            return;
+        }
+        self.last_sent = Instant::now();
+        let stats = mem::replace(&mut self.stats, TimingStats::new());
+        if let Some(send) = &self.send {
+            // The reporter never stops, so a failed send only drops these stats
+            send.send(stats).ok();
+        }
+    }
+}
+
+// Stats Reporter ----------------------------------------------------------------------------------
+
+/// Starts a thread that logs the merged stats of every `STATS_REPORT_INTERVAL_MS`. Once the clock
+/// stops and no more stats arrive, it logs histograms of everything since the clock started.
+pub fn start_stats_reporter() -> Sender<TimingStats> {
+    let (stats_send, stats_recv): (Sender<TimingStats>, Receiver<TimingStats>) = unbounded();
+    let report_timer = tick(Duration::from_millis(STATS_REPORT_INTERVAL_MS));
+
+    thread::spawn(move || {
+        let mut window = TimingStats::new();
+        let mut session = TimingStats::new();
+
+        loop {
+            select! {
+                recv(stats_recv) -> msg => {
+                    let stats = msg.expect("stats channel closed");
+                    window.merge(&stats);
+                    session.merge(&stats);
+                }
+                recv(report_timer) -> _ => {
+                    if !window.is_empty() {
+                        log::info(window.summary(), 0);
+                        window = TimingStats::new();
+                    } else if !session.is_empty() {
+                        log_session(&session);
+                        session = TimingStats::new();
+                    }
+                }
+            }
+        }
+    });
+
+    stats_send
+}
+
+fn log_session(session: &TimingStats) {
+    log::event(session.summary().replacen("STATS", "SESSION STATS", 1), 0);
+    let histograms = [
+        ("clock interval", &session.clock_interval),
+        ("tick latency", &session.tick_latency),
+        ("write latency", &session.write_latency),
+    ];
+    for (name, histogram) in histograms.iter() {
+        println!("{} ({} samples)", name, histogram.count());
+        for line in histogram.chart() {
+            println!("{}", line);
+        }
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::{bucket_index, bucket_range, Histogram, TimingStats, HISTOGRAM_BUCKETS};
+
+    #[test]
+    fn test_buckets() {
+        for micros in [0, 1, 15, 16, 17, 31, 32, 33, 1000, 20_833, 1_000_000].iter() {
+            let (low, width) = bucket_range(bucket_index(*micros));
+            assert!(low <= *micros && *micros < low + width, "{} not in {}+{}", micros, low, width);
+            assert!(width == 1 || width * 16 <= low);
+        }
+        assert_eq!(bucket_index(31), 31);
+        assert_eq!(bucket_index(u64::MAX), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = Histogram::new();
+        for micros in 1..=100 {
+            histogram.record_micros(micros);
+        }
+        assert_eq!(histogram.percentile(10.0), 10);
+        assert_eq!(histogram.percentile(50.0), 51);
+        assert_eq!(histogram.percentile(100.0), 100);
+        assert!((histogram.mean() - 50.5).abs() < 1e-9);
+
+        let mut stats = TimingStats::new();
+        assert!(stats.is_empty());
+        let mut other = TimingStats::new();
+        other.write_latency.merge(&histogram);
+        other.early_clocks = 2;
+        stats.merge(&other);
+        assert_eq!((stats.write_latency.count(), stats.write_latency.max, stats.early_clocks), (100, 100, 2));
+    }
+}
@@ -0,0 +1,326 @@
+/*
+ * Copyright 2020, Ian Zieg
+ *
+ * This file is part of a program called "cfgseq"
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use std::time::{Duration, Instant};
+
+use crate::config::{CLOCK_MULTIPLIER, TEMPO_BANDWIDTH, TEMPO_OUTLIER_RATIO, TEMPO_RELOCK_OUTLIERS};
+
+// Tempo Follower ----------------------------------------------------------------------------------
+
+const MIN_PERIOD_US: f64 = 1_000.0;
+const MAX_PERIOD_US: f64 = 1_000_000.0;
+
+/// Follows the tempo of an external MIDI clock with a second order delay-locked loop. Each clock
+/// corrects the predicted time of the next one and the clock period by a fraction of the error, so
+/// jitter is smoothed out while tempo ramps are tracked.
+///
+/// Clocks that are off by more than `TEMPO_OUTLIER_RATIO` of a period are ignored, until
+/// `TEMPO_RELOCK_OUTLIERS` of them in a row show that the tempo jumped and the loop locks again.
+pub struct TempoFollower {
+    origin: Instant,
+    last: Option<f64>,
+    locked: bool,
+    next: f64,
+    period: f64,
+    outliers: usize,
+    outlier: bool,
+}
+
+impl TempoFollower {
+    /// Creates a follower that assumes `period` until the second clock arrives.
+    pub fn new(period: Duration) -> TempoFollower {
+        TempoFollower {
+            origin: Instant::now(),
+            last: None,
+            locked: false,
+            next: 0.0,
+            period: period.as_micros() as f64,
+            outliers: 0,
+            outlier: false,
+        }
+    }
+
+    /// Forgets the phase when the clock stops, the period is kept as a guess for the next start.
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.locked = false;
+        self.outliers = 0;
+        self.outlier = false;
+    }
+
+    /// Takes the arrival of a clock and returns the predicted arrival of the next one, which is
+    /// always at least half a period away.
+    pub fn clock(&mut self, time: Instant) -> Instant {
+        if self.last.is_none() {
+            self.origin = time;
+        }
+        let t = time.saturating_duration_since(self.origin).as_micros() as f64;
+        self.outlier = false;
+
+        match self.last {
+            None => self.next = t + self.period,
+            Some(last) if !self.locked => {
+                self.period = clamp_period(t - last);
+                self.next = t + self.period;
+                self.locked = true;
+            }
+            Some(last) => {
+                let error = t - self.next;
+                if error.abs() <= self.period * TEMPO_OUTLIER_RATIO {
+                    self.next += self.period + std::f64::consts::SQRT_2 * TEMPO_BANDWIDTH * error;
+                    self.period = clamp_period(self.period + TEMPO_BANDWIDTH * TEMPO_BANDWIDTH * error);
+                    self.outliers = 0;
+                } else if self.outliers + 1 < TEMPO_RELOCK_OUTLIERS {
+                    // Keep the prediction, the clock may only have been delayed
+                    self.next += self.period;
+                    self.outliers += 1;
+                    self.outlier = true;
+                } else {
+                    self.period = clamp_period(t - last);
+                    self.next = t + self.period;
+                    self.outliers = 0;
+                    self.outlier = true;
+                }
+            }
+        }
+        self.next = self.next.max(t + self.period / 2.0);
+        self.last = Some(t);
+
+        self.origin + Duration::from_micros(self.next as u64)
+    }
+
+    /// Whether the last clock was ignored as an outlier.
+    pub fn was_outlier(&self) -> bool {
+        self.outlier
+    }
+
+    pub fn bpm(&self, ppq: u64) -> f64 {
+        60_000_000.0 / (self.period * ppq as f64)
+    }
+}
+
+fn clamp_period(period: f64) -> f64 {
+    period.clamp(MIN_PERIOD_US, MAX_PERIOD_US)
+}
+
+// Tick Schedule -----------------------------------------------------------------------------------
+
+/// Spreads `CLOCK_MULTIPLIER` ticks between each clock and the predicted arrival of the next one.
+/// Each clock continues the spacing from the last tick that was sent instead of starting over, so
+/// jitter of the clock doesn't bunch up ticks, and ticks that weren't sent yet when a clock arrives
+/// early are spread over the next clock along with its own instead of being sent at once.
+pub struct TickSchedule {
+    sent: u64,
+    first: u64,
+    end: u64,
+    start: Instant,
+    deadline: Instant,
+    last_due: Option<Instant>,
+}
+
+impl TickSchedule {
+    pub fn new() -> TickSchedule {
+        let now = Instant::now();
+        TickSchedule {
+            sent: 0,
+            first: 0,
+            end: 0,
+            start: now,
+            deadline: now,
+            last_due: None,
+        }
+    }
+
+    /// Starts over at the first tick, for when the clock stopped.
+    pub fn reset(&mut self) {
+        self.sent = 0;
+        self.first = 0;
+        self.end = 0;
+        self.last_due = None;
+    }
+
+    /// Schedules the ticks of a clock that arrived at `time` until the `next` one, and returns how
+    /// many ticks of the previous clocks were carried over.
+    pub fn clock(&mut self, time: Instant, next: Instant) -> u64 {
+        let carried = self.end - self.sent;
+        self.end += CLOCK_MULTIPLIER;
+        self.first = self.sent;
+
+        // The schedule starts one tick before the first one, which is due when the clock arrives
+        // unless the ticks sent so far are still closer
+        let spacing = next.saturating_duration_since(time) / (self.end - self.first) as u32;
+        let start = time.checked_sub(spacing).unwrap_or(time);
+        self.start = self.last_due.map_or(start, |due| due.max(start));
+        self.deadline = next.max(self.start);
+        carried
+    }
+
+    /// The number of the tick that is due next, counting from when the clock started running, and
+    /// when it is due. There is none after all ticks until the next clock were sent.
+    pub fn next_tick(&self) -> Option<(u64, Instant)> {
+        if self.sent >= self.end {
+            return None;
+        }
+        let span = (self.deadline - self.start).as_nanos();
+        let offset = span * (self.sent - self.first + 1) as u128 / (self.end - self.first) as u128;
+        Some((self.sent, self.start + Duration::from_nanos(offset as u64)))
+    }
+
+    pub fn advance(&mut self) {
+        if let Some((_, due)) = self.next_tick() {
+            self.last_due = Some(due);
+            self.sent += 1;
+        }
+    }
+}
+
+// Tests -------------------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::config::CLOCK_MULTIPLIER;
+    use crate::tempo::{TempoFollower, TickSchedule};
+
+    const PERIOD_120_BPM: f64 = 20_833.3;
+
+    /// Clock arrival times in microseconds for the given periods, each shifted by up to `jitter`.
+    fn clock_stream(periods: &[f64], jitter: f64) -> Vec<f64> {
+        let mut seed: u64 = 12345;
+        let mut time = 0.0;
+        periods
+            .iter()
+            .map(|period| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let noise = ((seed >> 33) as f64 / (1u64 << 31) as f64 * 2.0 - 1.0) * jitter;
+                time += period;
+                time + noise
+            })
+            .collect()
+    }
+
+    fn at(base: Instant, micros: f64) -> Instant {
+        base + Duration::from_micros(micros as u64)
+    }
+
+    /// The prediction error for every clock after the first `skip`, against the next arrival
+    /// without jitter.
+    fn prediction_errors(follower: &mut TempoFollower, periods: &[f64], jitter: f64, skip: usize) -> Vec<f64> {
+        let base = Instant::now();
+        let times = clock_stream(periods, jitter);
+        let ideal = clock_stream(periods, 0.0);
+        let mut errors = Vec::new();
+        for index in 0..times.len() - 1 {
+            let next = follower.clock(at(base, times[index]));
+            let predicted = (next - base).as_micros() as f64;
+            if index >= skip {
+                errors.push((predicted - ideal[index + 1]).abs());
+            }
+        }
+        errors
+    }
+
+    #[test]
+    fn test_follow_jitter() {
+        let mut follower = TempoFollower::new(Duration::from_micros(25_000));
+        let errors = prediction_errors(&mut follower, &vec![PERIOD_120_BPM; 480], 1_000.0, 96);
+        let max_error = errors.iter().cloned().fold(0.0, f64::max);
+        assert!(max_error < 750.0, "max error {}", max_error);
+        assert!((follower.bpm(24) - 120.0).abs() < 0.5, "bpm {}", follower.bpm(24));
+    }
+
+    #[test]
+    fn test_follow_ramp() {
+        // 120 to 150 BPM over 4 bars, then steady
+        let mut periods = (0..384)
+            .map(|i| PERIOD_120_BPM + (16_666.7 - PERIOD_120_BPM) * i as f64 / 384.0)
+            .collect::<Vec<_>>();
+        periods.extend(vec![16_666.7; 96]);
+
+        let mut follower = TempoFollower::new(Duration::from_micros(25_000));
+        let errors = prediction_errors(&mut follower, &periods, 200.0, 48);
+        let max_error = errors.iter().cloned().fold(0.0, f64::max);
+        assert!(max_error < 750.0, "max error {}", max_error);
+        assert!((follower.bpm(24) - 150.0).abs() < 0.5, "bpm {}", follower.bpm(24));
+    }
+
+    #[test]
+    fn test_outliers() {
+        let base = Instant::now();
+        let mut follower = TempoFollower::new(Duration::from_micros(25_000));
+        for index in 0..96 {
+            follower.clock(at(base, index as f64 * PERIOD_120_BPM));
+            assert!(!follower.was_outlier());
+        }
+
+        // A single delayed clock doesn't change the tempo
+        follower.clock(at(base, 96.0 * PERIOD_120_BPM + 8_000.0));
+        assert!(follower.was_outlier());
+        let next = follower.clock(at(base, 97.0 * PERIOD_120_BPM));
+        assert!(!follower.was_outlier());
+        assert!((follower.bpm(24) - 120.0).abs() < 0.1, "bpm {}", follower.bpm(24));
+        assert!(next > at(base, 98.0 * PERIOD_120_BPM - 50.0) && next < at(base, 98.0 * PERIOD_120_BPM + 50.0));
+
+        // A tempo jump to 60 BPM locks again after a few clocks
+        let start = 97.0 * PERIOD_120_BPM;
+        for index in 1..=3 {
+            follower.clock(at(base, start + index as f64 * PERIOD_120_BPM * 2.0));
+            assert!(follower.was_outlier());
+        }
+        follower.clock(at(base, start + 4.0 * PERIOD_120_BPM * 2.0));
+        assert!(!follower.was_outlier());
+        assert!((follower.bpm(24) - 60.0).abs() < 0.5, "bpm {}", follower.bpm(24));
+    }
+
+    #[test]
+    fn test_tick_schedule() {
+        let base = Instant::now();
+        // The tempo doubles halfway, so the first clocks after it arrive before all ticks were sent
+        let mut periods = vec![PERIOD_120_BPM; 48];
+        periods.extend(vec![PERIOD_120_BPM / 2.0; 48]);
+        let times = clock_stream(&periods, 300.0);
+
+        let mut follower = TempoFollower::new(Duration::from_micros(20_833));
+        let mut schedule = TickSchedule::new();
+        let mut ticks: Vec<(u64, Instant)> = Vec::new();
+        let mut carried = 0;
+        for time in times.iter().map(|t| at(base, *t)) {
+            // Send the ticks that were due before this clock arrived
+            while let Some((tick, due)) = schedule.next_tick() {
+                if due >= time {
+                    break;
+                }
+                ticks.push((tick, due));
+                schedule.advance();
+            }
+            let next = follower.clock(time);
+            carried += schedule.clock(time, next);
+        }
+
+        assert!(carried > 0);
+        for (index, (tick, _)) in ticks.iter().enumerate() {
+            assert_eq!(*tick, index as u64);
+        }
+        // No bursts, ticks are never closer than a third of their spacing at the faster tempo
+        let min_spacing = Duration::from_micros((PERIOD_120_BPM / 2.0 / CLOCK_MULTIPLIER as f64 / 3.0) as u64);
+        for pair in ticks.windows(2) {
+            assert!(pair[1].1 - pair[0].1 >= min_spacing, "burst at tick {}", pair[1].0);
+        }
+    }
+}